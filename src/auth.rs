@@ -0,0 +1,285 @@
+//! Logging in to services, to get the refresh tokens that runs use.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use data_encoding::BASE64URL_NOPAD;
+use eyre::{WrapErr, bail, eyre};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::net::TcpListener;
+
+use crate::{
+    JsonRequest, Secret, Service,
+    http::{read_target, respond},
+    receive,
+    spotify::Spotify,
+    tidal::Tidal,
+    tokens::TokenStore,
+};
+
+/// Enough to identify ourselves to a service, without anything that's tied to a user.
+#[derive(Deserialize, Debug)]
+pub struct AppSettings {
+    client_id: String,
+    client_secret: Secret<String>,
+}
+
+/// Everything we need to read and replace the contents of a playlist, even a private one.
+const SPOTIFY_SCOPES: &str = "playlist-read-private playlist-modify-public playlist-modify-private";
+
+/// Log in to Spotify with the authorization code flow and PKCE, and store the refresh
+/// token we get.
+///
+/// Spotify redirects the browser to a listener on the given local port, at
+/// `http://127.0.0.1:<port>/callback`, which has to be one of the app's redirect URIs.
+pub async fn spotify(
+    client: &reqwest::Client,
+    app: &AppSettings,
+    port: u16,
+    tokens: &TokenStore,
+) -> eyre::Result<()> {
+    let redirect_uri = format!("http://127.0.0.1:{port}/callback");
+    let verifier = random_string();
+    let challenge = BASE64URL_NOPAD.encode(&Sha256::digest(verifier.as_bytes()));
+    let state = random_string();
+
+    let url = reqwest::Url::parse_with_params(
+        "https://accounts.spotify.com/authorize",
+        &[
+            ("response_type", "code"),
+            ("client_id", &app.client_id),
+            ("redirect_uri", &redirect_uri),
+            ("scope", SPOTIFY_SCOPES),
+            ("state", &state),
+            ("code_challenge_method", "S256"),
+            ("code_challenge", &challenge),
+        ],
+    )?;
+
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    println!("Open this link, and log in as the owner of the playlist:");
+    println!();
+    println!("  {url}");
+    println!();
+    println!("If Spotify complains about the redirect URI, add {redirect_uri} to your app.");
+    let code = receive_code(&listener, &state).await?;
+
+    #[derive(Deserialize)]
+    struct Response {
+        refresh_token: Secret<String>,
+    }
+
+    let response: Response = client
+        .post("https://accounts.spotify.com/api/token")
+        .basic_auth(&app.client_id, Some(app.client_secret.expose_secret()))
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", &code),
+            ("redirect_uri", &redirect_uri),
+            ("code_verifier", &verifier),
+        ])
+        .send_it_secret_json()
+        .await?;
+
+    tokens
+        .update(Spotify::NAME, |credentials| {
+            credentials.refresh_token = Some(response.refresh_token);
+            // They may be for someone else.
+            credentials.access_tokens.clear();
+        })
+        .await?;
+    println!("Saved the refresh token to {}", tokens.path().display());
+    Ok(())
+}
+
+/// Everything we need to read and replace the contents of the user's playlists.
+const TIDAL_SCOPES: &str = "r_usr w_usr w_sub";
+
+/// Log in to Tidal with the device authorization flow, and store the refresh token we get
+/// along with the client, since the token only works for the client it was issued to.
+pub async fn tidal(
+    client: &reqwest::Client,
+    app: &AppSettings,
+    tokens: &TokenStore,
+) -> eyre::Result<()> {
+    const TOKEN_URL: &str = "https://auth.tidal.com/v1/oauth2/token";
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct DeviceResponse {
+        device_code: Secret<String>,
+        user_code: String,
+        verification_uri_complete: String,
+        expires_in: u64,
+        interval: u64,
+    }
+
+    #[derive(Deserialize)]
+    struct Response {
+        refresh_token: Secret<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct ErrorResponse {
+        error: String,
+    }
+
+    let device: DeviceResponse = client
+        .post("https://auth.tidal.com/v1/oauth2/device_authorization")
+        .form(&[
+            ("client_id", app.client_id.as_str()),
+            ("scope", TIDAL_SCOPES),
+        ])
+        .send_it_secret_json()
+        .await?;
+
+    let mut url = device.verification_uri_complete;
+    if !url.starts_with("http") {
+        url = format!("https://{url}");
+    }
+    println!("Open this link, and log in as the owner of the playlist:");
+    println!();
+    println!("  {url}");
+    println!();
+    println!("Tidal should show the code {}.", device.user_code);
+
+    let deadline = Instant::now() + Duration::from_secs(device.expires_in);
+    let mut interval = Duration::from_secs(device.interval.max(1));
+    let refresh_token = loop {
+        if Instant::now() > deadline {
+            bail!("the login link expired before it was used; try again");
+        }
+        tokio::time::sleep(interval).await;
+
+        let response = client
+            .post(TOKEN_URL)
+            .basic_auth(&app.client_id, Some(app.client_secret.expose_secret()))
+            .form(&[
+                ("client_id", app.client_id.as_str()),
+                ("device_code", device.device_code.expose_secret()),
+                ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+                ("scope", TIDAL_SCOPES),
+            ])
+            .send()
+            .await?;
+        if response.status().is_success() {
+            break receive::<Response>(response, true).await?.refresh_token;
+        }
+
+        let status = response.status();
+        let error = response
+            .json::<ErrorResponse>()
+            .await
+            .wrap_err_with(|| format!("polling for the login failed with {status}"))?;
+        match error.error.as_str() {
+            // The user hasn't finished logging in yet.
+            "authorization_pending" => (),
+            "slow_down" => interval += Duration::from_secs(5),
+            "expired_token" => bail!("the login link expired before it was used; try again"),
+            other => bail!("login failed: {other}"),
+        }
+    };
+
+    tokens
+        .update(Tidal::NAME, |credentials| {
+            credentials.client_id = Some(app.client_id.clone());
+            credentials.client_secret = Some(app.client_secret.clone());
+            credentials.refresh_token = Some(refresh_token);
+            // They may be for another client or user.
+            credentials.access_tokens.clear();
+        })
+        .await?;
+    println!(
+        "Saved the client and refresh token to {}",
+        tokens.path().display()
+    );
+    Ok(())
+}
+
+/// 32 random bytes, in a form that's fine in a URL. Good for both a PKCE verifier and an
+/// OAuth state.
+fn random_string() -> String {
+    let mut bytes = [0; 32];
+    rand::fill(&mut bytes);
+    BASE64URL_NOPAD.encode(&bytes)
+}
+
+/// Wait for the browser to be redirected back to us, and take the authorization code
+/// from the redirect.
+async fn receive_code(listener: &TcpListener, state: &str) -> eyre::Result<String> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (stream, Some(target)) = read_target(stream).await? else {
+            continue;
+        };
+        let url = reqwest::Url::parse("http://127.0.0.1")?.join(&target)?;
+        if url.path() != "/callback" {
+            // Probably the browser asking for a favicon.
+            respond(stream, "404 Not Found", "").await?;
+            continue;
+        }
+
+        let params: HashMap<_, _> = url.query_pairs().collect();
+        let result = if params.get("state").map(|s| s.as_ref()) != Some(state) {
+            Err(eyre!("the redirect wasn't for our login request"))
+        } else if let Some(code) = params.get("code") {
+            Ok(code.to_string())
+        } else {
+            let error = params.get("error").map_or("unknown error", |e| e.as_ref());
+            Err(eyre!("login failed: {error}"))
+        };
+
+        let body = match &result {
+            Ok(_) => "Logged in; you can close this tab.",
+            Err(_) => "Logging in failed; the terminal has the details.",
+        };
+        respond(stream, "200 OK", body).await?;
+        return result;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::receive_code;
+
+    async fn get(port: u16, target: &str) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let request = format!("GET {target} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_receive_code() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let browser = tokio::spawn(async move {
+            assert!(get(port, "/favicon.ico").await.starts_with("HTTP/1.1 404"));
+            get(port, "/callback?code=abc%2Fdef&state=xyz").await
+        });
+        assert_eq!("abc/def", receive_code(&listener, "xyz").await.unwrap());
+        assert!(browser.await.unwrap().starts_with("HTTP/1.1 200"));
+
+        let browser =
+            tokio::spawn(async move { get(port, "/callback?error=access_denied&state=xyz").await });
+        let error = receive_code(&listener, "xyz").await.unwrap_err();
+        assert!(error.to_string().contains("access_denied"));
+        browser.await.unwrap();
+
+        let browser =
+            tokio::spawn(async move { get(port, "/callback?code=abc&state=other").await });
+        assert!(receive_code(&listener, "xyz").await.is_err());
+        browser.await.unwrap();
+    }
+}
@@ -1,17 +1,133 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::{File, TryLockError},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use dashmap::DashMap;
 use futures::{StreamExt, stream::FuturesOrdered};
+use metrics::counter;
 use serde::{Deserialize, Serialize};
 use strsim::normalized_damerau_levenshtein;
-use tracing::{Span, error};
+use tracing::{Instrument, Span, debug_span, error, info};
 
-use crate::{Record, track::Track};
+use crate::{Record, overrides::Override, track::Track};
+use store::{JsonStore, SqliteStore, Store};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 struct CachedRecord {
     record: Record,
     rejected: bool,
+    /// Whether a person has looked at this rejection and agreed with it.
+    #[serde(default)]
+    reviewed: bool,
+}
+
+mod migrate;
+pub mod store;
+
+/// The version of the cache file format that we write.
+const VERSION: u64 = 3;
+
+/// Write a file such that a crash part way through leaves either the old contents or the
+/// new ones, never a mix.
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> eyre::Result<()> {
+    write_atomic_with(path, contents, 0o666)
+}
+
+/// Like [`write_atomic`], but nobody but the current user can read the file. For
+/// anything holding secrets.
+pub(crate) fn write_private(path: &Path, contents: &[u8]) -> eyre::Result<()> {
+    write_atomic_with(path, contents, 0o600)
+}
+
+/// `mode` is what the file is created with on unix, before the umask.
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn write_atomic_with(path: &Path, contents: &[u8], mode: u32) -> eyre::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(parent)?;
+
+    // A name of its own, so that two processes writing the same file at once can't
+    // trample each other's half-written copies.
+    let mut builder = tempfile::Builder::new();
+    builder
+        .prefix(path.file_name().unwrap_or_default())
+        .suffix(".tmp");
+    #[cfg(unix)]
+    builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(mode));
+    let mut file = builder.tempfile_in(parent)?;
+    file.write_all(contents)?;
+    file.as_file().sync_all()?;
+    file.persist(path)?;
+    Ok(())
+}
+
+/// An advisory lock on the cache directory, held for as long as this lives.
+///
+/// Runs that overlap (e.g. a slow hourly run and the next one) would otherwise clobber
+/// each other's caches. Commands that only read the cache can share it with each other,
+/// but not with a run.
+pub struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    fn open(dir: &Path) -> eyre::Result<File> {
+        std::fs::create_dir_all(dir)?;
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(".lock"))?;
+        Ok(file)
+    }
+
+    /// Take the lock if nobody else has it.
+    pub fn try_acquire(dir: &Path) -> eyre::Result<Option<Self>> {
+        let file = Self::open(dir)?;
+        Self::locked(file.try_lock(), file)
+    }
+
+    /// Take a share of the lock, for reading the cache, if nobody has all of it.
+    pub fn try_acquire_shared(dir: &Path) -> eyre::Result<Option<Self>> {
+        let file = Self::open(dir)?;
+        Self::locked(file.try_lock_shared(), file)
+    }
+
+    fn locked(result: Result<(), TryLockError>, file: File) -> eyre::Result<Option<Self>> {
+        match result {
+            Ok(()) => Ok(Some(Self { _file: file })),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(error)) => Err(error.into()),
+        }
+    }
+
+    /// Take the lock, waiting for whoever has it to finish.
+    pub async fn acquire(dir: &Path) -> eyre::Result<Self> {
+        let file = Self::open(dir)?;
+        let file = tokio::task::spawn_blocking(move || file.lock().map(|()| file)).await??;
+        Ok(Self { _file: file })
+    }
+
+    /// Take the lock for a run, waiting for whoever has it if `wait` is set. `None` if
+    /// somebody else has it and we're not to wait.
+    pub async fn acquire_for_run(dir: &Path, wait: bool) -> eyre::Result<Option<Self>> {
+        match Self::try_acquire(dir)? {
+            Some(lock) => Ok(Some(lock)),
+            None if wait => {
+                info!(dir = %dir.display(), "another run is using the cache; waiting for it");
+                Ok(Some(Self::acquire(dir).await?))
+            }
+            None => Ok(None),
+        }
+    }
 }
 
 /// Attempt to make a canonical representation of the artist.
@@ -29,73 +145,348 @@ fn title_str(title: &str) -> String {
         .collect::<String>()
 }
 
+/// How similar a search result is to the track we searched for, from 0 to 1.
+#[derive(Debug, Clone, Copy)]
+pub struct Scores {
+    pub title: f64,
+    /// The score of the best matching artist.
+    pub artist: f64,
+}
+
+impl Scores {
+    fn new(record: &Record, track: &Track) -> Self {
+        let title =
+            normalized_damerau_levenshtein(&title_str(&track.title), &title_str(&record.title));
+        let artist = record
+            .artists
+            .iter()
+            .map(|artist| {
+                normalized_damerau_levenshtein(&artist_str(&track.artist), &artist_str(artist))
+            })
+            .fold(0.0, f64::max);
+
+        Self { title, artist }
+    }
+}
+
 impl CachedRecord {
-    fn new(record: Record, track: &Track) -> Self {
+    /// `isrc` is the ISRC another service already matched this track to, if any.
+    fn new(record: Record, track: &Track, isrc: Option<&str>) -> Self {
         // It's possible we got a search hit, but it's not a real match, and
         // we should filter it out.
         //
         // This is just a guess at a decent heuristic.
         let threshold = 0.7;
 
-        let rejected =
-            normalized_damerau_levenshtein(&title_str(&track.title), &title_str(&record.title))
-                < threshold
-                || record.artists.iter().all(|artist| {
-                    normalized_damerau_levenshtein(&artist_str(&track.artist), &artist_str(artist))
-                        < threshold
-                });
+        let scores = Scores::new(&record, track);
+        // If it's the same recording another service matched, we trust that over the
+        // heuristic.
+        let same_recording = isrc.is_some() && record.isrc.as_deref() == isrc;
+        let rejected = !same_recording && (scores.title < threshold || scores.artist < threshold);
 
-        Self { record, rejected }
+        Self {
+            record,
+            rejected,
+            reviewed: false,
+        }
     }
 }
 
+/// How a search result fares against the track it was a search for.
+#[derive(Debug, Clone, Copy)]
+pub struct Verdict {
+    pub scores: Scores,
+    pub rejected: bool,
+}
+
+impl Verdict {
+    /// Judge the record the same way a run would, given the ISRCs other services have
+    /// matched so far.
+    pub fn new(record: &Record, track: &Track, isrcs: &Isrcs) -> Self {
+        let isrc = isrcs.get(track);
+        let cached = CachedRecord::new(record.clone(), track, isrc.as_deref());
+        Self {
+            scores: Scores::new(record, track),
+            rejected: cached.rejected,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct CacheEntry {
+    record: Option<CachedRecord>,
+    /// When we last searched for this track. Like the other timestamps, this is in
+    /// seconds since the unix epoch.
+    searched_at: u64,
+    /// When the track first showed up in a scrape.
+    first_seen: u64,
+    /// When the track last showed up in a scrape.
+    last_seen: u64,
+}
+
+impl CacheEntry {
+    fn new(record: Option<CachedRecord>) -> Self {
+        let now = now();
+        Self {
+            record,
+            searched_at: now,
+            first_seen: now,
+            last_seen: now,
+        }
+    }
+
+    fn is_expired(&self, expiry: &Expiry) -> bool {
+        let ttl = match &self.record {
+            None => expiry.not_found,
+            Some(record) if record.rejected => expiry.rejected,
+            // Positive hits never expire.
+            Some(_) => None,
+        };
+        ttl.is_some_and(|ttl| now().saturating_sub(self.searched_at) >= ttl.as_secs())
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// How long to keep cache entries. `None` means forever.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Expiry {
+    /// For tracks the search didn't find at all. New releases often show up on
+    /// streaming services hours after they're posted, so it's worth retrying them.
+    pub not_found: Option<Duration>,
+    /// For tracks the search found, but which we rejected.
+    pub rejected: Option<Duration>,
+    /// For tracks that are no longer being scraped. Reposts are common, so it's worth
+    /// remembering them for a while.
+    pub unseen: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// One JSON file per service.
+    #[default]
+    Json,
+    /// One SQLite database for all services.
+    Sqlite,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    /// Where to keep the cache; it's only kept in memory if not set.
+    pub dir: Option<PathBuf>,
+    pub backend: Backend,
+    pub expiry: Expiry,
+    /// If another run is using the cache directory, wait for it rather than skipping
+    /// this run.
+    pub wait_for_lock: bool,
+}
+
+impl Settings {
+    /// Open the configured store, if we have somewhere to keep it.
+    pub fn store(&self) -> eyre::Result<Option<Arc<dyn Store>>> {
+        let Some(dir) = &self.dir else {
+            return Ok(None);
+        };
+        let store: Arc<dyn Store> = match self.backend {
+            Backend::Json => Arc::new(JsonStore::new(dir.clone())),
+            Backend::Sqlite => Arc::new(SqliteStore::open(&dir.join(SqliteStore::FILE))?),
+        };
+        Ok(Some(store))
+    }
+}
+
+/// The ISRCs of tracks we've matched, shared between services. Once one service has
+/// found a track, the others can look it up by ISRC, which is much more precise than a
+/// text search.
+#[derive(Clone, Default)]
+pub struct Isrcs {
+    map: Arc<DashMap<Track, String>>,
+}
+
+impl Isrcs {
+    pub fn get(&self, track: &Track) -> Option<String> {
+        self.map.get(track).map(|isrc| isrc.clone())
+    }
+
+    /// Remember the ISRC of a record that was accepted for the track.
+    pub(crate) fn accept(&self, track: &Track, record: &Record) {
+        if let Some(isrc) = &record.isrc {
+            self.map.insert(track.clone(), isrc.clone());
+        }
+    }
+
+    fn insert(&self, track: &Track, record: &CachedRecord) {
+        if let Some(isrc) = &record.record.isrc
+            && !record.rejected
+        {
+            self.map.insert(track.clone(), isrc.clone());
+        }
+    }
+}
+
+/// What we know about a track, for inspecting the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Status {
+    Found,
+    NotFound,
+    Rejected,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Status::Found => "found",
+            Status::NotFound => "not found",
+            Status::Rejected => "rejected",
+        })
+    }
+}
+
+/// A cache entry, for inspecting the cache.
+pub struct Summary {
+    pub track: Track,
+    pub status: Status,
+    pub record: Option<Record>,
+    pub searched_at: u64,
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+/// A search result that we rejected, and nobody has reviewed yet.
+pub struct Rejection {
+    pub track: Track,
+    pub record: Record,
+    pub scores: Scores,
+}
+
 #[derive(Default)]
 pub struct Cache {
-    map: Arc<DashMap<Track, Option<CachedRecord>>>,
+    map: Arc<DashMap<Track, CacheEntry>>,
+    /// Consulted before the map, and never trimmed or serialized.
+    overrides: Arc<DashMap<Track, Override>>,
+    expiry: Expiry,
+    isrcs: Isrcs,
 }
 
 pub struct CacheResult {
     record: eyre::Result<Option<CachedRecord>>,
     cache_hit: bool,
+    overridden: bool,
 }
 
 impl Clone for Cache {
     fn clone(&self) -> Self {
         Self {
             map: self.map.clone(),
+            overrides: self.overrides.clone(),
+            expiry: self.expiry,
+            isrcs: self.isrcs.clone(),
         }
     }
 }
 
-impl<'de> Deserialize<'de> for Cache {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+impl Serialize for Cache {
+    fn serialize<Ser>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error>
     where
-        D: serde::Deserializer<'de>,
+        Ser: serde::Serializer,
     {
-        let map = Vec::deserialize(deserializer)?.into_iter().collect();
+        #[derive(Serialize)]
+        struct Envelope {
+            version: u64,
+            entries: Vec<(Track, CacheEntry)>,
+        }
 
-        Ok(Self { map: Arc::new(map) })
+        Envelope {
+            version: VERSION,
+            entries: self.entries(),
+        }
+        .serialize(serializer)
     }
 }
 
-impl Serialize for Cache {
-    fn serialize<Ser>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error>
-    where
-        Ser: serde::Serializer,
-    {
-        let vec: Vec<_> = self
-            .map
+impl Cache {
+    /// Load a cache file of any known version, migrating it to the current one.
+    pub fn load(path: &Path, service: &str) -> eyre::Result<Self> {
+        Self::from_json(&std::fs::read_to_string(path)?, service)
+    }
+
+    pub fn from_json(json: &str, service: &str) -> eyre::Result<Self> {
+        let value = serde_json::from_str(json)?;
+        let entries = migrate::entries(value, service)?;
+        Ok(Self::from_entries(entries))
+    }
+
+    fn from_entries(entries: impl IntoIterator<Item = (Track, CacheEntry)>) -> Self {
+        Self {
+            map: Arc::new(entries.into_iter().collect()),
+            overrides: Default::default(),
+            expiry: Default::default(),
+            isrcs: Default::default(),
+        }
+    }
+
+    fn entries(&self) -> Vec<(Track, CacheEntry)> {
+        self.map
             .iter()
             .map(|entry| {
                 let (k, v) = entry.pair();
                 (k.clone(), v.clone())
             })
-            .collect();
-        vec.serialize(serializer)
+            .collect()
+    }
+
+    pub fn save(&self, path: &Path) -> eyre::Result<()> {
+        let ser = serde_json::to_string(self)?;
+        write_atomic(path, ser.as_bytes())
+    }
+
+    pub fn with_overrides(mut self, overrides: HashMap<Track, Override>) -> Self {
+        self.overrides = Arc::new(overrides.into_iter().collect());
+        self
+    }
+
+    /// Swap the overrides for new ones, in this cache and every clone of it.
+    pub fn replace_overrides(&self, overrides: HashMap<Track, Override>) {
+        self.overrides.clear();
+        for (track, action) in overrides {
+            self.overrides.insert(track, action);
+        }
+    }
+
+    /// Swap the entries for another cache's, e.g. one that's just been loaded again, in
+    /// this cache and every clone of it.
+    pub fn replace_entries(&self, other: Cache) {
+        self.map.clear();
+        for (track, entry) in other.entries() {
+            if let Some(record) = &entry.record {
+                self.isrcs.insert(&track, record);
+            }
+            self.map.insert(track, entry);
+        }
+    }
+
+    pub fn with_expiry(mut self, expiry: Expiry) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    /// Share ISRCs with other services, starting with the ones already in this cache.
+    pub fn with_isrcs(mut self, isrcs: Isrcs) -> Self {
+        for entry in self.map.iter() {
+            if let Some(record) = &entry.record {
+                isrcs.insert(entry.key(), record);
+            }
+        }
+        self.isrcs = isrcs;
+        self
     }
-}
 
-impl Cache {
     pub async fn with_cache<
         'a,
         F: FnOnce(&'a Track) -> Fut,
@@ -105,29 +496,77 @@ impl Cache {
         track: &'a Track,
         search: F,
     ) -> CacheResult {
-        if let Some(record) = self.map.get(track) {
+        if let Some(action) = self.overrides.get(track) {
+            let record = match &*action {
+                Override::Never => None,
+                Override::Pin(id) => Some(CachedRecord {
+                    record: Record {
+                        id: id.clone(),
+                        title: track.title.clone(),
+                        artists: vec![track.artist.clone()],
+                        isrc: None,
+                    },
+                    rejected: false,
+                    reviewed: false,
+                }),
+            };
+            return CacheResult {
+                record: Ok(record),
+                cache_hit: false,
+                overridden: true,
+            };
+        }
+
+        let previous = self.map.get(track).map(|entry| entry.clone());
+        let first_seen = previous.as_ref().map(|entry| entry.first_seen);
+        if let Some(entry) = &previous
+            && !entry.is_expired(&self.expiry)
+        {
             // Cache hit; we've searched for this track before, even if we didn't find it.
             return CacheResult {
-                record: Ok(record.clone()),
+                record: Ok(entry.record.clone()),
                 cache_hit: true,
+                overridden: false,
             };
         }
 
+        let isrc = self.isrcs.get(track);
         let record = match search(track).await {
-            Ok(record) => record.map(|r| CachedRecord::new(r, track)),
+            Ok(record) => record.map(|r| {
+                let mut record = CachedRecord::new(r, track, isrc.as_deref());
+                // Don't ask about the same rejection twice.
+                if let Some(CachedRecord {
+                    record: prev,
+                    reviewed: true,
+                    ..
+                }) = previous.and_then(|entry| entry.record)
+                {
+                    record.reviewed = prev.id == record.record.id;
+                }
+                record
+            }),
             Err(error) => {
                 return CacheResult {
                     record: Err(error),
                     cache_hit: false,
+                    overridden: false,
                 };
             }
         };
 
-        self.map.insert(track.clone(), record.clone());
+        if let Some(record) = &record {
+            self.isrcs.insert(track, record);
+        }
+        let mut entry = CacheEntry::new(record.clone());
+        if let Some(first_seen) = first_seen {
+            entry.first_seen = first_seen;
+        }
+        self.map.insert(track.clone(), entry);
 
         CacheResult {
             record: Ok(record),
             cache_hit: false,
+            overridden: false,
         }
     }
 
@@ -137,50 +576,181 @@ impl Cache {
         Fut: Future<Output = eyre::Result<Option<Record>>>,
     >(
         &self,
+        service: &'static str,
         tracks: &'a [Track],
         search: F,
     ) -> impl Iterator<Item = Record> {
-        let futures = tracks
-            .iter()
-            .map(|track| self.with_cache(track, search.clone()));
+        let futures = tracks.iter().map(|track| {
+            let span = debug_span!("search", track = %track);
+            self.with_cache(track, search.clone()).instrument(span)
+        });
         let results = FuturesOrdered::from_iter(futures).collect::<Vec<_>>().await;
         let cache_hits = results.iter().filter(|r| r.cache_hit).count();
         Span::current().record("cache_hits", cache_hits);
-        // let rejected = results.iter().filter(|r| r.record).count();
-        // Span::current().record("filtered", filtered);
+        let overridden = results.iter().filter(|r| r.overridden).count();
+        Span::current().record("overridden", overridden);
+        let searches = results.len() - cache_hits - overridden;
+        // Only what was searched for this time, so that cached and overridden results
+        // aren't counted again on every run.
+        let searched = |r: &&CacheResult| !r.cache_hit && !r.overridden;
+        let not_found = results
+            .iter()
+            .filter(searched)
+            .filter(|r| matches!(r.record, Ok(None)))
+            .count();
+        let rejected = results
+            .iter()
+            .filter(searched)
+            .filter(|r| matches!(&r.record, Ok(Some(record)) if record.rejected))
+            .count();
+        counter!("playlister_searches_total", "service" => service).increment(searches as u64);
+        counter!("playlister_cache_hits_total", "service" => service).increment(cache_hits as u64);
+        counter!("playlister_not_found_total", "service" => service).increment(not_found as u64);
 
         let records = results
             .into_iter()
-            .map(|r| r.record)
-            .filter_map(|r| {
-                if let Err(error) = &r {
-                    error!(%error, "search failed");
+            .zip(tracks)
+            .filter_map(|(r, track)| {
+                if let Err(error) = &r.record {
+                    error!(%error, %track, "search failed");
                 }
-                r.ok().flatten()
+                r.record.ok().flatten()
             })
             .collect::<Vec<_>>();
 
-        let rejected = records.iter().filter(|r| r.rejected).count();
-        Span::current().record("rejected", rejected);
+        Span::current().record("rejected", records.iter().filter(|r| r.rejected).count());
+        counter!("playlister_rejections_total", "service" => service).increment(rejected as u64);
 
         records
             .into_iter()
             .filter_map(|r| if r.rejected { None } else { Some(r.record) })
     }
 
+    /// Mark the given tracks as seen, and drop anything that hasn't been seen for longer
+    /// than we keep entries for.
     pub fn trim(&self, tracks: &[Track]) {
+        let now = now();
         let tracks = tracks.iter().collect::<HashSet<_>>();
-        self.map.retain(|k, _v| tracks.contains(k));
+        self.map.retain(|k, v| {
+            if tracks.contains(k) {
+                v.last_seen = now;
+                return true;
+            }
+            self.expiry
+                .unseen
+                .is_none_or(|ttl| now.saturating_sub(v.last_seen) < ttl.as_secs())
+        });
+    }
+
+    pub fn summaries(&self) -> Vec<Summary> {
+        self.map
+            .iter()
+            .map(|entry| {
+                let (track, entry) = entry.pair();
+                let status = match &entry.record {
+                    None => Status::NotFound,
+                    Some(record) if record.rejected => Status::Rejected,
+                    Some(_) => Status::Found,
+                };
+                Summary {
+                    track: track.clone(),
+                    status,
+                    record: entry.record.as_ref().map(|r| r.record.clone()),
+                    searched_at: entry.searched_at,
+                    first_seen: entry.first_seen,
+                    last_seen: entry.last_seen,
+                }
+            })
+            .collect()
+    }
+
+    /// Forget a track, so that it's searched for again. Returns whether it was cached.
+    pub fn evict(&self, track: &Track) -> bool {
+        self.map.remove(track).is_some()
+    }
+
+    /// Forget every track that wasn't found or was rejected. Returns how many there were.
+    pub fn evict_negatives(&self) -> usize {
+        let before = self.map.len();
+        self.map
+            .retain(|_k, v| v.record.as_ref().is_some_and(|record| !record.rejected));
+        before - self.map.len()
+    }
+
+    /// Add all of `other`'s entries, replacing any we already have.
+    pub fn merge(&self, other: Cache) {
+        for (track, entry) in other.entries() {
+            self.map.insert(track, entry);
+        }
+    }
+
+    /// All rejected records that have not been reviewed yet.
+    pub fn rejections(&self) -> Vec<Rejection> {
+        self.map
+            .iter()
+            .filter_map(|entry| {
+                let (track, entry) = entry.pair();
+                let record = entry.record.as_ref()?;
+                if !record.rejected || record.reviewed {
+                    return None;
+                }
+                Some(Rejection {
+                    track: track.clone(),
+                    record: record.record.clone(),
+                    scores: Scores::new(&record.record, track),
+                })
+            })
+            .collect()
+    }
+
+    /// Mark a rejection as wrong; the record will be used from now on.
+    pub fn approve(&self, track: &Track) {
+        if let Some(mut entry) = self.map.get_mut(track)
+            && let Some(record) = entry.record.as_mut()
+        {
+            record.rejected = false;
+            record.reviewed = true;
+        }
+    }
+
+    /// Mark a rejection as correct, so we don't ask about it again.
+    pub fn confirm(&self, track: &Track) {
+        if let Some(mut entry) = self.map.get_mut(track)
+            && let Some(record) = entry.record.as_mut()
+        {
+            record.reviewed = true;
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+    use std::{
+        collections::HashMap,
+        sync::atomic::{AtomicU32, Ordering::SeqCst},
+        time::Duration,
+    };
 
-    use crate::{Record, track::Track};
+    use metrics_exporter_prometheus::PrometheusBuilder;
 
-    use super::Cache;
+    use crate::{Record, overrides::Override, track::Track};
+
+    use super::{Cache, Expiry, Isrcs, Lock};
+
+    /// What a search finds for `title` by `artist`.
+    fn record(id: &str, title: &str, artist: &str) -> Record {
+        Record {
+            id: id.into(),
+            title: title.into(),
+            artists: vec![artist.into()],
+            isrc: None,
+        }
+    }
+
+    /// A search that only finds tracks by `foo`.
+    fn find(track: &Track) -> Option<Record> {
+        (track.artist == "foo").then(|| record("aaa", &track.title, "foo"))
+    }
 
     #[tokio::test]
     async fn test_cache() {
@@ -192,15 +762,7 @@ mod test {
 
         let search = async |track: &Track| {
             searches.fetch_add(1, SeqCst);
-            if track.artist == "foo" {
-                Ok(Some(Record {
-                    id: "aaa".into(),
-                    title: "N/A".into(),
-                    artists: Vec::new(),
-                }))
-            } else {
-                Ok(None)
-            }
+            Ok(find(track))
         };
 
         let cache = <Cache>::default();
@@ -210,7 +772,7 @@ mod test {
 
         let str = serde_json::to_string(&cache).unwrap();
         dbg!(&str);
-        let cache: Cache = serde_json::from_str(&str).unwrap();
+        let cache = Cache::from_json(&str, "test").unwrap();
 
         cache.with_cache(&found, search).await.record.unwrap();
         assert_eq!(2, searches.load(SeqCst));
@@ -220,4 +782,207 @@ mod test {
         cache.with_cache(&new_track, search).await.record.unwrap();
         assert_eq!(3, searches.load(SeqCst));
     }
+
+    #[tokio::test]
+    async fn test_overrides() {
+        let pinned = Track::new("foo".into(), "fife".into());
+        let never = Track::new("bar".into(), "bibe".into());
+
+        let searches = AtomicU32::new(0);
+
+        let search = async |_track: &Track| {
+            searches.fetch_add(1, SeqCst);
+            Ok(Some(record("aaa", "fife", "foo")))
+        };
+
+        let overrides = HashMap::from([
+            (pinned.clone(), Override::Pin("bbb".into())),
+            (never.clone(), Override::Never),
+        ]);
+        let cache = Cache::default().with_overrides(overrides);
+        cache.trim(&[]);
+
+        let record = cache.with_cache(&pinned, search).await.record.unwrap();
+        assert_eq!("bbb", record.unwrap().record.id);
+        let record = cache.with_cache(&never, search).await.record.unwrap();
+        assert!(record.is_none());
+        assert_eq!(0, searches.load(SeqCst));
+    }
+
+    #[test]
+    fn test_counters() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        let tracks = [Track::new("bar".into(), "bibe".into())];
+        let search = async |_track: &Track| Ok(None);
+
+        // Only the first run searches; the others find the same thing in the cache, and
+        // it's not counted again.
+        let cache = Cache::default();
+        for _ in 0..3 {
+            metrics::with_local_recorder(&recorder, || {
+                futures::executor::block_on(cache.get_all("spotify", &tracks, search)).count()
+            });
+        }
+        let rendered = handle.render();
+        assert!(rendered.contains(r#"playlister_not_found_total{service="spotify"} 1"#));
+        assert!(rendered.contains(r#"playlister_cache_hits_total{service="spotify"} 2"#));
+    }
+
+    #[tokio::test]
+    async fn test_replace() {
+        let track = Track::new("foo".into(), "fife".into());
+        let search = async |track: &Track| Ok(find(track));
+
+        // A clone, like the one a service searches with, sees what's swapped in.
+        let cache = Cache::default();
+        let clone = cache.clone();
+        let reloaded = Cache::default();
+        reloaded.with_cache(&track, search).await.record.unwrap();
+        cache.replace_entries(reloaded);
+        assert!(clone.with_cache(&track, search).await.cache_hit);
+
+        cache.replace_overrides(HashMap::from([(track.clone(), Override::Never)]));
+        let result = clone.with_cache(&track, search).await;
+        assert!(result.overridden);
+        assert!(result.record.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_review() {
+        let track = Track::new("foo".into(), "fife".into());
+        let search = async |_track: &Track| Ok(Some(record("aaa", "something else", "foo")));
+
+        let cache = Cache::default();
+        let record = cache.with_cache(&track, search).await.record.unwrap();
+        assert!(record.unwrap().rejected);
+
+        let rejections = cache.rejections();
+        assert_eq!(1, rejections.len());
+        assert_eq!(1.0, rejections[0].scores.artist);
+
+        cache.confirm(&track);
+        assert!(cache.rejections().is_empty());
+
+        cache.approve(&track);
+        let record = cache.with_cache(&track, search).await.record.unwrap();
+        assert!(!record.unwrap().rejected);
+    }
+
+    #[tokio::test]
+    async fn test_expiry() {
+        let found = Track::new("foo".into(), "fife".into());
+        let not_found = Track::new("bar".into(), "bibe".into());
+
+        let searches = AtomicU32::new(0);
+
+        let search = async |track: &Track| {
+            searches.fetch_add(1, SeqCst);
+            Ok(find(track))
+        };
+
+        let cache = Cache::default().with_expiry(Expiry {
+            not_found: Some(Duration::ZERO),
+            rejected: Some(Duration::ZERO),
+            unseen: None,
+        });
+        cache.with_cache(&found, search).await.record.unwrap();
+        cache.with_cache(&not_found, search).await.record.unwrap();
+        assert_eq!(2, searches.load(SeqCst));
+
+        // Only the negative result gets searched again.
+        cache.with_cache(&found, search).await.record.unwrap();
+        cache.with_cache(&not_found, search).await.record.unwrap();
+        assert_eq!(3, searches.load(SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_lock() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let lock = Lock::try_acquire(dir.path()).unwrap();
+        assert!(lock.is_some());
+        assert!(Lock::try_acquire(dir.path()).unwrap().is_none());
+
+        assert!(Lock::try_acquire_shared(dir.path()).unwrap().is_none());
+
+        drop(lock);
+        let shared = Lock::try_acquire_shared(dir.path()).unwrap();
+        assert!(shared.is_some());
+        assert!(Lock::try_acquire_shared(dir.path()).unwrap().is_some());
+        assert!(Lock::try_acquire(dir.path()).unwrap().is_none());
+
+        drop(shared);
+        assert!(Lock::try_acquire(dir.path()).unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_retention() {
+        let seen = Track::new("foo".into(), "fife".into());
+        let unseen = Track::new("bar".into(), "bibe".into());
+        let search = async |_track: &Track| Ok(None);
+
+        let cache = Cache::default().with_expiry(Expiry {
+            unseen: Some(Duration::from_secs(60)),
+            ..Expiry::default()
+        });
+        cache.with_cache(&seen, search).await.record.unwrap();
+        cache.with_cache(&unseen, search).await.record.unwrap();
+
+        // Still within the retention window.
+        cache.trim(std::slice::from_ref(&seen));
+        assert_eq!(2, cache.entries().len());
+
+        let cache = cache.with_expiry(Expiry {
+            unseen: Some(Duration::ZERO),
+            ..Expiry::default()
+        });
+        cache.trim(std::slice::from_ref(&seen));
+        let entries = cache.entries();
+        assert_eq!(1, entries.len());
+        assert_eq!(seen, entries[0].0);
+    }
+
+    #[tokio::test]
+    async fn test_isrcs() {
+        let track = Track::new("foo".into(), "fife".into());
+        let isrcs = Isrcs::default();
+
+        let spotify = Cache::default().with_isrcs(isrcs.clone());
+        let search = async |_track: &Track| {
+            Ok(Some(Record {
+                isrc: Some("USRC17607839".into()),
+                ..record("aaa", "fife", "foo")
+            }))
+        };
+        spotify.with_cache(&track, search).await.record.unwrap();
+        assert_eq!(Some("USRC17607839".into()), isrcs.get(&track));
+
+        // The title is way off, but it's the same recording, so we keep it.
+        let tidal = Cache::default().with_isrcs(isrcs.clone());
+        let title = "fife - 2011 remaster, live at the hollywood bowl";
+        let search = async |_track: &Track| {
+            Ok(Some(Record {
+                isrc: Some("USRC17607839".into()),
+                ..record("bbb", title, "foo")
+            }))
+        };
+        let record = tidal.with_cache(&track, search).await.record.unwrap();
+        assert!(!record.unwrap().rejected);
+    }
+
+    #[tokio::test]
+    async fn test_evict_negatives() {
+        let found = Track::new("foo".into(), "fife".into());
+        let not_found = Track::new("bar".into(), "bibe".into());
+        let search = async |track: &Track| Ok(find(track));
+
+        let cache = Cache::default();
+        cache.with_cache(&found, search).await.record.unwrap();
+        cache.with_cache(&not_found, search).await.record.unwrap();
+
+        assert_eq!(1, cache.evict_negatives());
+        assert!(cache.evict(&found));
+        assert!(cache.summaries().is_empty());
+    }
 }
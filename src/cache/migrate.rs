@@ -0,0 +1,223 @@
+//! Older layouts of the cache file, and how to bring them up to date.
+
+use eyre::{bail, eyre};
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::{CacheEntry, CachedRecord, VERSION};
+use crate::{Record, track::Track};
+
+/// Read the entries out of a cache file of any version we know about.
+pub(super) fn entries(value: Value, service: &str) -> eyre::Result<Vec<(Track, CacheEntry)>> {
+    match version(&value)? {
+        0 => v0(value, service),
+        1 => v1(value),
+        2 => v2(value),
+        VERSION => current(value),
+        version => bail!(
+            "cache file has format version {version}, but we only understand up to {VERSION}; \
+            was it written by a newer playlister?"
+        ),
+    }
+}
+
+fn version(value: &Value) -> eyre::Result<u64> {
+    match value {
+        Value::Object(obj) => obj
+            .get("version")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| eyre!("cache file is missing its version")),
+        // Before the envelope, the file was a bare list of pairs. The oldest layout keyed
+        // tracks with `track` rather than `title`.
+        Value::Array(entries) => {
+            let is_v0 = entries
+                .first()
+                .and_then(|entry| entry.get(0))
+                .is_some_and(|track| track.get("track").is_some());
+            Ok(if is_v0 { 0 } else { 1 })
+        }
+        _ => bail!("unrecognized cache file format"),
+    }
+}
+
+fn current(value: Value) -> eyre::Result<Vec<(Track, CacheEntry)>> {
+    #[derive(Deserialize)]
+    struct Envelope {
+        entries: Vec<(Track, CacheEntry)>,
+    }
+
+    let envelope: Envelope = serde_json::from_value(value)?;
+    Ok(envelope.entries)
+}
+
+/// We don't know when entries from older versions were searched for, so they're treated
+/// as being as old as possible.
+fn unknown_age(record: Option<CachedRecord>) -> CacheEntry {
+    CacheEntry {
+        record,
+        searched_at: 0,
+        first_seen: 0,
+        last_seen: 0,
+    }
+}
+
+/// Before we kept entries for tracks that dropped off the front page, so we didn't track
+/// when they were seen.
+fn v2(value: Value) -> eyre::Result<Vec<(Track, CacheEntry)>> {
+    #[derive(Deserialize)]
+    struct V2Entry {
+        record: Option<CachedRecord>,
+        searched_at: u64,
+    }
+
+    #[derive(Deserialize)]
+    struct Envelope {
+        entries: Vec<(Track, V2Entry)>,
+    }
+
+    let envelope: Envelope = serde_json::from_value(value)?;
+    Ok(envelope
+        .entries
+        .into_iter()
+        .map(|(track, entry)| {
+            let entry = CacheEntry {
+                record: entry.record,
+                searched_at: entry.searched_at,
+                first_seen: entry.searched_at,
+                last_seen: entry.searched_at,
+            };
+            (track, entry)
+        })
+        .collect())
+}
+
+/// A bare list of `(Track, Option<CachedRecord>)`, before we kept timestamps.
+fn v1(value: Value) -> eyre::Result<Vec<(Track, CacheEntry)>> {
+    let entries: Vec<(Track, Option<CachedRecord>)> = serde_json::from_value(value)?;
+    Ok(entries
+        .into_iter()
+        .map(|(track, record)| (track, unknown_age(record)))
+        .collect())
+}
+
+/// One file shared by all services, before we checked search results for bad matches.
+fn v0(value: Value, service: &str) -> eyre::Result<Vec<(Track, CacheEntry)>> {
+    #[derive(Deserialize)]
+    struct V0Track {
+        artist: String,
+        track: String,
+    }
+
+    #[derive(Deserialize)]
+    struct V0Records {
+        tidal: Option<V0Tidal>,
+        spotify: Option<V0Spotify>,
+    }
+
+    #[derive(Deserialize)]
+    struct V0Tidal {
+        id: String,
+    }
+
+    #[derive(Deserialize)]
+    struct V0Spotify {
+        uri: String,
+        name: String,
+    }
+
+    let entries: Vec<(V0Track, V0Records)> = serde_json::from_value(value)?;
+    entries
+        .into_iter()
+        .map(|(track, records)| {
+            let V0Track { artist, track } = track;
+            let (id, title) = match service {
+                "tidal" => match records.tidal {
+                    // Tidal records didn't keep the title; the search hit is all we've got.
+                    Some(tidal) => (tidal.id, track.clone()),
+                    None => return Ok((Track::new(artist, track), unknown_age(None))),
+                },
+                "spotify" => match records.spotify {
+                    Some(spotify) => (spotify.uri, spotify.name),
+                    None => return Ok((Track::new(artist, track), unknown_age(None))),
+                },
+                _ => bail!("the version 0 cache format has no records for {service}"),
+            };
+            // These were already in the playlist, so keep them rather than second-guessing
+            // them now.
+            let record = CachedRecord {
+                record: Record {
+                    id,
+                    title,
+                    artists: vec![artist.clone()],
+                    isrc: None,
+                },
+                rejected: false,
+                reviewed: false,
+            };
+            Ok((Track::new(artist, track), unknown_age(Some(record))))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use crate::track::Track;
+
+    use super::entries;
+
+    #[test]
+    fn test_v0() {
+        let value = json!([
+            [
+                { "artist": "Ovel Peddy", "track": "Set in Stone" },
+                {
+                    "tidal": { "id": "423632049" },
+                    "spotify": { "uri": "spotify:track:4bLz944b08gR0vKbqlFsId", "name": "Set in Stone" }
+                }
+            ],
+            [
+                { "artist": "Goodtree", "track": "My Mom's Dog" },
+                { "tidal": null, "spotify": { "uri": "spotify:track:6E3mONNhKL3qaNavHjISd9", "name": "my mom's dog" } }
+            ]
+        ]);
+
+        let tidal = entries(value.clone(), "tidal").unwrap();
+        assert_eq!(2, tidal.len());
+        assert_eq!(
+            Track::new("Ovel Peddy".into(), "Set in Stone".into()),
+            tidal[0].0
+        );
+        assert_eq!("423632049", tidal[0].1.record.as_ref().unwrap().record.id);
+        assert!(tidal[1].1.record.is_none());
+
+        let spotify = entries(value, "spotify").unwrap();
+        assert_eq!(
+            "spotify:track:6E3mONNhKL3qaNavHjISd9",
+            spotify[1].1.record.as_ref().unwrap().record.id
+        );
+    }
+
+    #[test]
+    fn test_v1() {
+        let value = json!([
+            [
+                { "artist": "foo", "title": "fife" },
+                { "record": { "id": "aaa", "title": "fife", "artists": ["foo"] }, "rejected": true }
+            ],
+            [{ "artist": "bar", "title": "bibe" }, null]
+        ]);
+
+        let entries = entries(value, "spotify").unwrap();
+        assert_eq!(2, entries.len());
+        assert!(entries[0].1.record.as_ref().unwrap().rejected);
+        assert!(entries[1].1.record.is_none());
+    }
+
+    #[test]
+    fn test_newer_version() {
+        let value = json!({ "version": 1000, "entries": [] });
+        assert!(entries(value, "spotify").is_err());
+    }
+}
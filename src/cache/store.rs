@@ -0,0 +1,363 @@
+//! Where caches are kept between runs.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use eyre::bail;
+use rusqlite::{Connection, params, types::Type};
+use tracing::info;
+
+use super::{Cache, CacheEntry, CachedRecord, now};
+use crate::{Record, track::Track};
+
+pub trait Store: Send + Sync {
+    /// Load the cache for the given service. If there isn't one yet, it's empty.
+    fn load(&self, service: &str) -> eyre::Result<Cache>;
+    fn save(&self, service: &str, cache: &Cache) -> eyre::Result<()>;
+    /// Which save of the service's cache, by us or anyone else, is the latest; `None` if
+    /// it hasn't been saved.
+    fn revision(&self, service: &str) -> Option<Revision>;
+}
+
+/// Tells saves of a service's cache apart, so that a change by someone else can be
+/// noticed without loading the cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Revision {
+    /// When the file it's saved in was last changed.
+    Modified(SystemTime),
+    /// How many times it's been saved.
+    Saves(i64),
+}
+
+/// One JSON file per service, rewritten in full on every save.
+pub struct JsonStore {
+    dir: PathBuf,
+}
+
+impl JsonStore {
+    /// Before each service had its own cache file, they shared this one.
+    const LEGACY_FILE: &str = "cache.json";
+
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Where the cache for the given service lives.
+    pub fn path(&self, service: &str) -> PathBuf {
+        self.dir.join(format!("{service}.json"))
+    }
+}
+
+impl Store for JsonStore {
+    /// If the file can't be read, it's moved aside rather than being overwritten by the
+    /// next save, so that a format problem never silently wipes a warm cache.
+    fn load(&self, service: &str) -> eyre::Result<Cache> {
+        let mut path = self.path(service);
+        if !path.exists() {
+            let legacy = self.dir.join(Self::LEGACY_FILE);
+            if !legacy.exists() {
+                return Ok(Cache::default());
+            }
+            info!(path = %legacy.display(), "migrating legacy cache file");
+            path = legacy;
+        }
+
+        Cache::load(&path, service).or_else(|error| {
+            if path.ends_with(Self::LEGACY_FILE) {
+                return Err(error);
+            }
+            let backup = path.with_extension(format!("json.{}.bak", now()));
+            std::fs::rename(&path, &backup)?;
+            Err(error.wrap_err(format!(
+                "failed to load cache; moved it to {}",
+                backup.display()
+            )))
+        })
+    }
+
+    fn save(&self, service: &str, cache: &Cache) -> eyre::Result<()> {
+        cache.save(&self.path(service))
+    }
+
+    fn revision(&self, service: &str) -> Option<Revision> {
+        let modified = std::fs::metadata(self.path(service)).and_then(|m| m.modified());
+        modified.ok().map(Revision::Modified)
+    }
+}
+
+/// A single SQLite database shared by all services. Only entries that changed are
+/// written on save.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+/// Each of these brings the schema from the previous version to the next; the current
+/// version is kept in `user_version`.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE entries (
+        service TEXT NOT NULL,
+        artist TEXT NOT NULL,
+        title TEXT NOT NULL,
+        searched_at INTEGER NOT NULL,
+        record_id TEXT,
+        record_title TEXT,
+        record_artists TEXT,
+        rejected INTEGER NOT NULL DEFAULT 0,
+        reviewed INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (service, artist, title)
+    );",
+    "ALTER TABLE entries ADD COLUMN first_seen INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE entries ADD COLUMN last_seen INTEGER NOT NULL DEFAULT 0;
+    UPDATE entries SET first_seen = searched_at, last_seen = searched_at;",
+    "ALTER TABLE entries ADD COLUMN record_isrc TEXT;",
+    "CREATE TABLE saves (
+        service TEXT PRIMARY KEY,
+        count INTEGER NOT NULL
+    );",
+];
+
+impl SqliteStore {
+    pub const FILE: &str = "cache.sqlite3";
+
+    pub fn open(path: &Path) -> eyre::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut conn = Connection::open(path)?;
+
+        let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version as usize > MIGRATIONS.len() {
+            bail!(
+                "cache database {} has schema version {version}, but we only understand up to {}; \
+                was it written by a newer playlister?",
+                path.display(),
+                MIGRATIONS.len()
+            );
+        }
+        let tx = conn.transaction()?;
+        for migration in MIGRATIONS.iter().skip(version as usize) {
+            tx.execute_batch(migration)?;
+        }
+        tx.pragma_update(None, "user_version", MIGRATIONS.len() as i64)?;
+        tx.commit()?;
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn entries(conn: &Connection, service: &str) -> eyre::Result<HashMap<Track, CacheEntry>> {
+        let mut stmt = conn.prepare(
+            "SELECT artist, title, searched_at, record_id, record_title, record_artists, rejected, reviewed,
+                first_seen, last_seen, record_isrc
+            FROM entries WHERE service = ?1",
+        )?;
+        let rows = stmt.query_map(params![service], |row| {
+            let track = Track::new(row.get(0)?, row.get(1)?);
+            let searched_at: i64 = row.get(2)?;
+            let id: Option<String> = row.get(3)?;
+            let record = match id {
+                Some(id) => {
+                    let artists: String = row.get(5)?;
+                    Some(CachedRecord {
+                        record: Record {
+                            id,
+                            title: row.get(4)?,
+                            artists: serde_json::from_str(&artists).map_err(|error| {
+                                rusqlite::Error::FromSqlConversionFailure(
+                                    5,
+                                    Type::Text,
+                                    Box::new(error),
+                                )
+                            })?,
+                            isrc: row.get(10)?,
+                        },
+                        rejected: row.get(6)?,
+                        reviewed: row.get(7)?,
+                    })
+                }
+                None => None,
+            };
+            let first_seen: i64 = row.get(8)?;
+            let last_seen: i64 = row.get(9)?;
+            let entry = CacheEntry {
+                record,
+                searched_at: searched_at as u64,
+                first_seen: first_seen as u64,
+                last_seen: last_seen as u64,
+            };
+            Ok((track, entry))
+        })?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
+
+impl Store for SqliteStore {
+    fn load(&self, service: &str) -> eyre::Result<Cache> {
+        let conn = self.conn.lock().unwrap();
+        let entries = Self::entries(&conn, service)?;
+        Ok(Cache::from_entries(entries))
+    }
+
+    fn save(&self, service: &str, cache: &Cache) -> eyre::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut stored = Self::entries(&tx, service)?;
+        let mut changed = false;
+
+        for (track, entry) in cache.entries() {
+            if stored.remove(&track).as_ref() == Some(&entry) {
+                continue;
+            }
+            changed = true;
+            let record = entry.record.as_ref();
+            let artists = record
+                .map(|r| serde_json::to_string(&r.record.artists))
+                .transpose()?;
+            tx.execute(
+                "INSERT OR REPLACE INTO entries
+                (service, artist, title, searched_at, record_id, record_title, record_artists, rejected, reviewed,
+                    first_seen, last_seen, record_isrc)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                params![
+                    service,
+                    track.artist,
+                    track.title,
+                    entry.searched_at as i64,
+                    record.map(|r| &r.record.id),
+                    record.map(|r| &r.record.title),
+                    artists,
+                    record.is_some_and(|r| r.rejected),
+                    record.is_some_and(|r| r.reviewed),
+                    entry.first_seen as i64,
+                    entry.last_seen as i64,
+                    record.and_then(|r| r.record.isrc.as_ref()),
+                ],
+            )?;
+        }
+
+        // Whatever's left has been trimmed from the cache.
+        for track in stored.keys() {
+            changed = true;
+            tx.execute(
+                "DELETE FROM entries WHERE service = ?1 AND artist = ?2 AND title = ?3",
+                params![service, track.artist, track.title],
+            )?;
+        }
+
+        if changed {
+            tx.execute(
+                "INSERT INTO saves (service, count) VALUES (?1, 1)
+                ON CONFLICT (service) DO UPDATE SET count = count + 1",
+                params![service],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Counted per service, as they share the file; saving one doesn't change another's.
+    fn revision(&self, service: &str) -> Option<Revision> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT count FROM saves WHERE service = ?1",
+            params![service],
+            |row| row.get(0),
+        )
+        .ok()
+        .map(Revision::Saves)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use rusqlite::Connection;
+
+    use crate::{
+        Record,
+        cache::{Cache, Expiry},
+        track::Track,
+    };
+
+    use super::{SqliteStore, Store};
+
+    #[tokio::test]
+    async fn test_sqlite() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SqliteStore::open(&dir.path().join(SqliteStore::FILE)).unwrap();
+
+        let found = Track::new("foo".into(), "fife".into());
+        let not_found = Track::new("bar".into(), "bibe".into());
+        let search = async |track: &Track| {
+            Ok((track.artist == "foo").then(|| Record {
+                id: "aaa".into(),
+                title: "fife".into(),
+                artists: vec!["foo".into()],
+                isrc: None,
+            }))
+        };
+
+        let cache = Cache::default();
+        cache.with_cache(&found, search).await.record.unwrap();
+        cache.with_cache(&not_found, search).await.record.unwrap();
+        store.save("spotify", &cache).unwrap();
+
+        let loaded = store.load("spotify").unwrap().with_expiry(Expiry {
+            unseen: Some(Duration::ZERO),
+            ..Expiry::default()
+        });
+        assert_eq!(cache.entries().len(), loaded.entries().len());
+        assert!(store.load("tidal").unwrap().entries().is_empty());
+
+        // Saving one service's cache doesn't make another's look changed, and saving
+        // it unchanged doesn't either.
+        let revision = store.revision("spotify");
+        assert!(revision.is_some());
+        store.save("tidal", &cache).unwrap();
+        store.save("spotify", &cache).unwrap();
+        assert_eq!(revision, store.revision("spotify"));
+
+        loaded.trim(std::slice::from_ref(&found));
+        store.save("spotify", &loaded).unwrap();
+        assert_ne!(revision, store.revision("spotify"));
+        let entries = store.load("spotify").unwrap().entries();
+        assert_eq!(1, entries.len());
+        assert_eq!(found, entries[0].0);
+        assert_eq!("aaa", entries[0].1.record.as_ref().unwrap().record.id);
+    }
+
+    #[test]
+    fn test_sqlite_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(SqliteStore::FILE);
+        let store = SqliteStore::open(&path).unwrap();
+
+        // A corrupt row is an error, rather than a record without any artists.
+        let conn = Connection::open(&path).unwrap();
+        conn.execute(
+            "INSERT INTO entries (service, artist, title, searched_at, record_id, record_title, record_artists)
+            VALUES ('spotify', 'foo', 'fife', 0, 'aaa', 'fife', 'not json')",
+            [],
+        )
+        .unwrap();
+        assert!(store.load("spotify").is_err());
+
+        // A database from a newer version is left alone.
+        conn.pragma_update(None, "user_version", 100).unwrap();
+        drop(store);
+        let error = SqliteStore::open(&path).err().unwrap();
+        assert!(error.to_string().contains("newer playlister"));
+        let version: i64 = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(100, version);
+    }
+}
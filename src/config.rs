@@ -0,0 +1,701 @@
+//! Where settings come from, and how they're put together into jobs.
+//!
+//! Settings are layered, lowest precedence first: the config file, the environment, the
+//! job, and the flags. Secrets can be given as files or commands, which are only read
+//! for the jobs and services a command uses.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    env, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::{self, Stdio},
+    time::Duration,
+};
+
+use ::config::{Config, ConfigError, Environment, Map, Value, ValueKind};
+use eyre::{OptionExt, WrapErr, bail, eyre};
+use itertools::Itertools;
+use serde::Deserialize;
+
+use crate::{
+    Context, Service,
+    cache::{self, Backend, Expiry},
+    overrides::Overrides,
+    reddit,
+    spotify::{self, Spotify},
+    tidal::{self, Tidal},
+    tokens::TokenStore,
+};
+
+/// The settings that are the same for every job.
+#[derive(Deserialize, Debug)]
+pub struct Global {
+    /// A level, or per-target directives like `info,playlister::tidal=debug`.
+    #[serde(default = "info")]
+    pub log_level: String,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    pub cache_dir: Option<PathBuf>,
+    pub state_dir: Option<PathBuf>,
+    /// Where `daemon` serves metrics for Prometheus, at `/metrics`.
+    pub metrics_address: Option<SocketAddr>,
+    /// An OpenTelemetry collector to send traces to, over OTLP/HTTP.
+    pub otlp_endpoint: Option<String>,
+}
+
+impl Global {
+    pub fn tokens(&self) -> Option<TokenStore> {
+        token_store(self.state_dir.as_deref(), self.cache_dir.as_deref())
+    }
+}
+
+/// How log lines are written.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One line per event, with the spans it's in.
+    #[default]
+    Full,
+    /// Like `full`, but shorter.
+    Compact,
+    /// Several lines per event, for reading at a terminal.
+    Pretty,
+    /// A JSON object per line, with the fields of the event and the spans it's in.
+    Json,
+}
+
+/// Where credentials from logging in are kept; the cache directory unless set.
+fn token_store(state_dir: Option<&Path>, cache_dir: Option<&Path>) -> Option<TokenStore> {
+    Some(TokenStore::new(state_dir.or(cache_dir)?))
+}
+
+/// The settings for one job.
+#[derive(Deserialize, Debug)]
+pub struct Settings {
+    pub cache_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub cache_backend: Backend,
+    #[serde(default, with = "humantime_serde")]
+    pub cache_not_found_ttl: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    pub cache_rejected_ttl: Option<Duration>,
+    #[serde(default = "ninety_days", with = "humantime_serde")]
+    pub cache_retention: Option<Duration>,
+    #[serde(default)]
+    pub cache_lock_wait: bool,
+    pub overrides_file: Option<PathBuf>,
+    pub state_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub source: reddit::Source,
+    /// How often `daemon` syncs; hourly unless this or `cron` is set.
+    #[serde(default, with = "humantime_serde")]
+    pub interval: Option<Duration>,
+    /// When `daemon` syncs, as a cron expression.
+    pub cron: Option<String>,
+    pub reddit: Option<reddit::Settings>,
+    pub spotify: Option<spotify::Settings>,
+    pub tidal: Option<tidal::Settings>,
+    /// The services the job has its own credentials for, and the job's name.
+    #[serde(skip)]
+    own_credentials: Vec<(&'static str, String)>,
+}
+
+impl Settings {
+    pub fn cache(&self) -> cache::Settings {
+        cache::Settings {
+            dir: self.cache_dir.clone(),
+            backend: self.cache_backend,
+            expiry: Expiry {
+                not_found: self.cache_not_found_ttl,
+                rejected: self.cache_rejected_ttl,
+                unseen: self.cache_retention,
+            },
+            wait_for_lock: self.cache_lock_wait,
+        }
+    }
+
+    pub fn overrides_file(&self) -> Option<PathBuf> {
+        self.overrides_file.clone().or_else(|| {
+            self.cache_dir
+                .as_ref()
+                .map(|dir| dir.join("overrides.json"))
+        })
+    }
+
+    pub fn tokens(&self) -> Option<TokenStore> {
+        let tokens = token_store(self.state_dir.as_deref(), self.cache_dir.as_deref())?;
+        Some(
+            self.own_credentials
+                .iter()
+                .fold(tokens, |tokens, (service, job)| tokens.scoped(service, job)),
+        )
+    }
+
+    /// Fill in the services' settings with the credentials we've stored.
+    fn with_stored_credentials(mut self) -> eyre::Result<Self> {
+        let Some(tokens) = self.tokens() else {
+            return Ok(self);
+        };
+        if let Some(spotify) = self.spotify {
+            self.spotify = Some(spotify.with_credentials(tokens.get(Spotify::NAME)?));
+        }
+        if let Some(tidal) = self.tidal {
+            self.tidal = Some(tidal.with_credentials(tokens.get(Tidal::NAME)?));
+        }
+        Ok(self)
+    }
+
+    pub fn overrides(&self) -> eyre::Result<Overrides> {
+        match self.overrides_file() {
+            Some(path) => Overrides::load(&path),
+            None => Ok(Overrides::default()),
+        }
+    }
+
+    /// Everything the services share. The cache store is left out, for the commands
+    /// that use it to add.
+    pub fn context(&self, client: &reqwest::Client) -> eyre::Result<Context> {
+        Ok(Context {
+            client: client.clone(),
+            store: None,
+            expiry: self.cache().expiry,
+            overrides: self.overrides()?,
+            isrcs: Default::default(),
+            tokens: self.tokens(),
+        })
+    }
+}
+
+/// A set of playlists to keep up to date from one source.
+pub struct Job {
+    /// Only jobs from a config file have names.
+    pub name: Option<String>,
+    /// What `settings` came from.
+    pub config: Config,
+    pub settings: Settings,
+}
+
+impl Job {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or("default")
+    }
+}
+
+/// Where settings come from, lowest precedence first: the config file, the environment,
+/// the job, and the global flags.
+pub struct Sources {
+    file: Config,
+    env: Config,
+    /// The file and the environment together.
+    base: Config,
+    flags: Vec<(&'static str, Option<String>)>,
+    /// What each `_command` printed, so that one that's shared by several jobs only has
+    /// to run once.
+    commands: RefCell<HashMap<String, String>>,
+}
+
+impl Sources {
+    /// Read the config file, if there is one, and the environment. `flags` are the
+    /// settings given on the command line, by name.
+    pub fn new(
+        config_file: Option<PathBuf>,
+        flags: Vec<(&'static str, Option<String>)>,
+    ) -> eyre::Result<Self> {
+        let mut file = Config::builder();
+        if let Some(path) = config_file.or_else(|| env::var_os("CONFIG_FILE").map(PathBuf::from)) {
+            file = file.add_source(::config::File::from(path));
+        }
+        let env = Config::builder()
+            .add_source(Environment::default().separator("__"))
+            .build()?;
+        Self::with_layers(file.build()?, env, flags)
+    }
+
+    fn with_layers(
+        file: Config,
+        env: Config,
+        flags: Vec<(&'static str, Option<String>)>,
+    ) -> eyre::Result<Self> {
+        let base = Config::builder()
+            .add_source(file.clone())
+            .add_source(env.clone())
+            .build()?;
+        Ok(Self {
+            file,
+            env,
+            base,
+            flags,
+            commands: Default::default(),
+        })
+    }
+
+    /// The settings outside of any job, without any secrets read from files or
+    /// commands.
+    pub fn top(&self) -> eyre::Result<Config> {
+        self.build(&[])
+    }
+
+    fn build(&self, job: &[(String, Value)]) -> eyre::Result<Config> {
+        let mut builder = Config::builder().add_source(self.base.clone());
+        for (key, value) in job {
+            builder = builder.set_override(key.as_str(), value.clone())?;
+        }
+        for (key, value) in &self.flags {
+            builder = builder.set_override_option(*key, value.clone())?;
+        }
+        Ok(builder.build()?)
+    }
+
+    /// The job that was asked for, or every job in the config file if none was, in
+    /// order of name. Without any, the settings outside of a job make up the only one.
+    ///
+    /// Only the `sections` a command uses (e.g. `spotify`) are kept, so that secrets it
+    /// has no use for aren't read; see [`Sources::resolve`].
+    pub fn jobs(&self, name: Option<&str>, sections: &[&str]) -> eyre::Result<Vec<Job>> {
+        let jobs = match self.base.get_table("jobs") {
+            Ok(jobs) => jobs,
+            Err(ConfigError::NotFound(_)) => {
+                if let Some(name) = name {
+                    bail!("there's no job named {name}; the jobs are: default");
+                }
+                let config = self.resolve(self.top()?, &[], sections)?;
+                let settings = config
+                    .clone()
+                    .try_deserialize::<Settings>()?
+                    .with_stored_credentials()?;
+                return Ok(vec![Job {
+                    name: None,
+                    config,
+                    settings,
+                }]);
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        let names = jobs.keys().sorted().join(", ");
+        let jobs = jobs
+            .into_iter()
+            .filter(|(job, _)| name.is_none_or(|name| name == job))
+            .sorted_by(|(a, _), (b, _)| a.cmp(b))
+            .collect::<Vec<_>>();
+        if let Some(name) = name
+            && jobs.is_empty()
+        {
+            bail!("there's no job named {name}; the jobs are: {names}");
+        }
+
+        jobs.into_iter()
+            .map(|(name, job)| {
+                let job = job
+                    .into_table()
+                    .wrap_err_with(|| format!("job {name} isn't a table"))?;
+                let mut overrides = Vec::new();
+                let mut own_credentials = Vec::new();
+                // A job only updates the playlists it has a section for, even if the
+                // rest of a service's settings are shared.
+                for service in SERVICES {
+                    match job.get(service) {
+                        None => overrides.push((service.to_owned(), ValueKind::Nil.into())),
+                        Some(section) if has_credentials(section) => {
+                            own_credentials.push((service, name.clone()))
+                        }
+                        Some(_) => (),
+                    }
+                }
+                flatten("", job, &mut overrides);
+
+                let config = self.resolve(self.build(&overrides)?, &overrides, sections)?;
+                let mut settings = config
+                    .clone()
+                    .try_deserialize::<Settings>()
+                    .wrap_err_with(|| format!("invalid settings for job {name}"))?;
+                settings.own_credentials = own_credentials;
+                let settings = settings.with_stored_credentials()?;
+                Ok(Job {
+                    name: Some(name),
+                    config,
+                    settings,
+                })
+            })
+            .collect()
+    }
+
+    /// Read the secrets in `sections` that are given as files or commands, and leave out
+    /// the other sections, whose secrets the command doesn't need.
+    ///
+    /// Where a secret is given more than one way, the way that's given by the source
+    /// with the highest precedence is used, as with any other setting.
+    pub fn resolve(
+        &self,
+        config: Config,
+        job: &[(String, Value)],
+        sections: &[&str],
+    ) -> eyre::Result<Config> {
+        let mut builder = Config::builder().add_source(config.clone());
+        for section in SECTIONS {
+            if !sections.contains(&section) {
+                builder = builder.set_override(section, ValueKind::Nil)?;
+            }
+        }
+
+        for section in sections {
+            for secret in SECRETS {
+                let key = format!("{section}.{secret}");
+                let file = format!("{key}_file");
+                let command = format!("{key}_command");
+                let given = [&key, &file, &command]
+                    .into_iter()
+                    .filter(|key| config.get::<Value>(key).is_ok())
+                    .map(|key| (self.precedence(key, job), key))
+                    .collect::<Vec<_>>();
+                let Some(highest) = given.iter().map(|(precedence, _)| *precedence).max() else {
+                    continue;
+                };
+                let chosen = given
+                    .into_iter()
+                    .filter(|(precedence, _)| *precedence == highest)
+                    .map(|(_, key)| key)
+                    .collect::<Vec<_>>();
+                let value = match chosen[..] {
+                    [chosen] if *chosen == file => {
+                        let path = config.get_string(chosen)?;
+                        fs::read_to_string(&path)
+                            .wrap_err_with(|| format!("couldn't read {chosen} {path}"))?
+                    }
+                    [chosen] if *chosen == command => {
+                        self.run_command(chosen, &config.get_string(chosen)?)?
+                    }
+                    [_] => continue,
+                    _ => bail!("both {} and {} are set; pick one", chosen[0], chosen[1]),
+                };
+                // Files and commands tend to add a newline.
+                builder = builder.set_override(key, value.trim_end())?;
+            }
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Which source a setting comes from, as its precedence: the file's settings are
+    /// lowest, then the environment's, then the job's.
+    fn precedence(&self, key: &str, job: &[(String, Value)]) -> u8 {
+        if job.iter().any(|(k, _)| k == key) {
+            2
+        } else if self.env.get::<Value>(key).is_ok() {
+            1
+        } else {
+            debug_assert!(self.file.get::<Value>(key).is_ok());
+            0
+        }
+    }
+
+    fn run_command(&self, key: &str, command: &str) -> eyre::Result<String> {
+        if let Some(output) = self.commands.borrow().get(command) {
+            return Ok(output.clone());
+        }
+        let output = run_command(key, command)?;
+        self.commands
+            .borrow_mut()
+            .insert(command.to_owned(), output.clone());
+        Ok(output)
+    }
+}
+
+/// Every value in a table, keyed by its dotted path, so that a job can override part of
+/// a section without replacing the rest of it.
+fn flatten(prefix: &str, table: Map<String, Value>, values: &mut Vec<(String, Value)>) {
+    for (key, value) in table {
+        let key = format!("{prefix}{key}");
+        match value.kind {
+            ValueKind::Table(table) => flatten(&format!("{key}."), table, values),
+            _ => values.push((key, value)),
+        }
+    }
+}
+
+/// Settings that tie a service to an account; a job that sets any of them has its
+/// stored credentials kept apart from the other jobs'.
+const CREDENTIALS: [&str; 3] = ["client_id", "client_secret", "refresh_token"];
+
+fn has_credentials(section: &Value) -> bool {
+    let Ok(section) = section.clone().into_table() else {
+        return false;
+    };
+    section.keys().any(|key| {
+        CREDENTIALS.iter().any(|credential| {
+            key.strip_prefix(credential)
+                .is_some_and(|rest| ["", "_file", "_command"].contains(&rest))
+        })
+    })
+}
+
+/// The services a job can have playlists on.
+const SERVICES: [&str; 2] = [Spotify::NAME, Tidal::NAME];
+
+/// The sections of the settings that have credentials in them.
+const SECTIONS: [&str; 3] = ["reddit", "spotify", "tidal"];
+
+/// Settings that can also be read from a file, or from what a command prints, by adding
+/// `_file` or `_command` to their names; e.g. `SPOTIFY__CLIENT_SECRET_FILE`. They're
+/// only read for the jobs and services a command uses.
+const SECRETS: [&str; 2] = ["client_secret", "refresh_token"];
+
+/// Run a command with the shell, and take what it prints. What it prints is never in an
+/// error, as it's a secret.
+fn run_command(key: &str, command: &str) -> eyre::Result<String> {
+    let output = process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        // A password manager might want to ask for something.
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .wrap_err_with(|| format!("couldn't run {key}"))?;
+    if !output.status.success() {
+        bail!("{key} failed with {}", output.status);
+    }
+    String::from_utf8(output.stdout).map_err(|_| eyre!("{key} printed something that isn't UTF-8"))
+}
+
+/// The job for a command that only works with one.
+pub fn select_one(mut jobs: Vec<Job>) -> eyre::Result<Job> {
+    if jobs.len() > 1 {
+        bail!(
+            "there are several jobs; pick one with --job: {}",
+            jobs.iter().map(Job::name).join(", ")
+        );
+    }
+    jobs.pop().ok_or_eyre("there are no jobs")
+}
+
+fn info() -> String {
+    "info".to_owned()
+}
+
+fn ninety_days() -> Option<Duration> {
+    Some(Duration::from_secs(90 * 24 * 60 * 60))
+}
+
+#[cfg(test)]
+mod test {
+    use ::config::{Config, File, FileFormat};
+
+    use crate::Secret;
+
+    use super::{Job, SECTIONS, Sources, select_one};
+
+    const CONFIG: &str = r#"
+        cache_backend = "sqlite"
+
+        [reddit]
+        client_id = "reddit"
+        client_secret = "secret"
+
+        [spotify]
+        client_id = "spotify"
+        client_secret = "secret"
+        refresh_token = "refresh"
+        playlist_id = "everyone"
+
+        [tidal]
+        client_id = "tidal"
+        client_secret = "secret"
+        refresh_token = "refresh"
+
+        [jobs.listentothis]
+        cache_dir = "/cache/listentothis"
+        spotify = {}
+        tidal = { playlist_id = "ltt" }
+
+        [jobs.metal]
+        cache_dir = "/cache/metal"
+        source = { subreddit = "Metal", sort = "top", min_score = 10, exclude = ["(?i)live"] }
+        spotify = { playlist_id = "metal", market = "DE" }
+    "#;
+
+    fn toml(toml: &str) -> Config {
+        Config::builder()
+            .add_source(File::from_str(toml, FileFormat::Toml))
+            .build()
+            .unwrap()
+    }
+
+    fn sources(flags: Vec<(&'static str, Option<String>)>) -> Sources {
+        Sources::with_layers(toml(CONFIG), toml(""), flags).unwrap()
+    }
+
+    #[test]
+    fn test_jobs() {
+        let jobs = sources(Vec::new()).jobs(None, &SECTIONS).unwrap();
+        let names = jobs.iter().map(|job| job.name()).collect::<Vec<_>>();
+        assert_eq!(vec!["listentothis", "metal"], names);
+
+        let listentothis = &jobs[0].settings;
+        assert_eq!(
+            "/cache/listentothis",
+            listentothis.cache_dir.as_ref().unwrap().to_str().unwrap()
+        );
+        assert_eq!("listentothis", listentothis.source.subreddit);
+        assert!(listentothis.reddit.is_some());
+        assert!(listentothis.spotify.is_some());
+        assert!(listentothis.tidal.is_some());
+
+        // Only what the job sets is changed.
+        let metal = &jobs[1].settings;
+        assert_eq!(
+            "/cache/metal",
+            metal.cache_dir.as_ref().unwrap().to_str().unwrap()
+        );
+        assert_eq!("Metal", metal.source.subreddit);
+        assert!(metal.reddit.is_some());
+        assert!(metal.spotify.is_some());
+        assert!(metal.tidal.is_none());
+
+        // Flags win over jobs.
+        let jobs = sources(vec![("cache_dir", Some("/elsewhere".into()))])
+            .jobs(None, &SECTIONS)
+            .unwrap();
+        assert!(
+            jobs.iter()
+                .all(|job| job.settings.cache_dir.as_ref().unwrap().to_str() == Some("/elsewhere"))
+        );
+    }
+
+    #[test]
+    fn test_select() {
+        let sources = sources(Vec::new());
+        let jobs = |name| sources.jobs(name, &SECTIONS);
+        assert_eq!(2, jobs(None).unwrap().len());
+        assert_eq!(
+            "metal",
+            select_one(jobs(Some("metal")).unwrap()).unwrap().name()
+        );
+        let error = jobs(Some("jazz")).err().unwrap();
+        assert!(error.to_string().contains("listentothis, metal"));
+        assert!(select_one(jobs(None).unwrap()).is_err());
+
+        // Only the sections that are asked for are kept.
+        let jobs = sources.jobs(None, &["spotify"]).unwrap();
+        assert!(jobs[0].settings.reddit.is_none());
+        assert!(jobs[0].settings.spotify.is_some());
+        assert!(jobs[0].settings.tidal.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_own_credentials() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = format!(
+            r#"
+            state_dir = "{}"
+            spotify = {{ client_id = "app", client_secret = "secret", refresh_token = "shared-0", playlist_id = "a" }}
+            [jobs.shared]
+            spotify = {{ playlist_id = "b" }}
+            [jobs.own]
+            spotify = {{ refresh_token = "own-0", playlist_id = "c" }}
+            "#,
+            dir.path().display()
+        );
+        let sources = Sources::with_layers(toml(&config), Config::default(), Vec::new()).unwrap();
+        let refresh_token = |job: &Job| {
+            let tokens = job.settings.tokens().unwrap();
+            let credentials = tokens.get("spotify").unwrap();
+            credentials
+                .refresh_token
+                .map(|token| token.expose_secret().to_owned())
+        };
+
+        // Each job stores its rotated refresh token...
+        for job in sources.jobs(None, &SECTIONS).unwrap() {
+            let rotated = format!("{}-1", job.name());
+            let tokens = job.settings.tokens().unwrap();
+            tokens
+                .update("spotify", |c| c.refresh_token = Some(Secret::new(rotated)))
+                .await
+                .unwrap();
+        }
+        // ...and gets back its own, not the other's.
+        let jobs = sources.jobs(None, &SECTIONS).unwrap();
+        assert_eq!("own", jobs[0].name());
+        assert_eq!(Some("own-1".to_owned()), refresh_token(&jobs[0]));
+        assert_eq!(Some("shared-1".to_owned()), refresh_token(&jobs[1]));
+    }
+
+    #[test]
+    fn test_secrets() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("secret");
+        std::fs::write(&file, "from a file\n").unwrap();
+
+        let resolve = |file: &str, env: &str, sections: &[&str]| {
+            Sources::with_layers(toml(file), toml(env), Vec::new())
+                .unwrap()
+                .jobs(None, sections)
+        };
+
+        let jobs = resolve(
+            &format!(
+                r#"
+                overrides_file = "overrides.json"
+                reddit = {{ client_id = "reddit", client_secret_command = "false" }}
+                spotify = {{ client_id = "spotify", client_secret_file = "{}", refresh_token_command = "echo from a command", playlist_id = "a" }}
+                tidal = {{ client_id = "tidal", refresh_token = "refresh" }}
+                [jobs.tidal]
+                spotify = {{}}
+                tidal = {{ client_secret_command = "printf 'in a job'", playlist_id = "b" }}
+                "#,
+                file.display()
+            ),
+            "",
+            &["spotify", "tidal"],
+        )
+        .unwrap();
+        let spotify = jobs[0].config.get_table("spotify").unwrap();
+        assert_eq!("from a file", spotify["client_secret"].to_string());
+        assert_eq!("from a command", spotify["refresh_token"].to_string());
+        assert_eq!(
+            "in a job",
+            jobs[0].config.get_string("tidal.client_secret").unwrap()
+        );
+        // Reddit's command isn't run, since Reddit isn't needed.
+        assert!(jobs[0].settings.reddit.is_none());
+        // Other settings ending in `_file` are left alone.
+        assert_eq!(
+            "overrides.json",
+            jobs[0].config.get_string("overrides_file").unwrap()
+        );
+
+        // The environment wins over the file, whichever way each gives a secret.
+        let spotify = r#"spotify = { client_id = "spotify", client_secret_file = "/nowhere", playlist_id = "a" }"#;
+        let jobs = resolve(
+            spotify,
+            r#"spotify = { client_secret = "from the environment" }"#,
+            &["spotify"],
+        );
+        assert_eq!(
+            "from the environment",
+            jobs.unwrap()[0]
+                .config
+                .get_string("spotify.client_secret")
+                .unwrap()
+        );
+
+        let error = resolve(
+            r#"spotify = { client_secret = "a", client_secret_command = "echo b" }"#,
+            "",
+            &["spotify"],
+        )
+        .err()
+        .unwrap();
+        assert!(error.to_string().contains("pick one"));
+
+        let error = resolve(
+            r#"spotify = { client_secret_command = "echo hunter2; false" }"#,
+            "",
+            &["spotify"],
+        )
+        .err()
+        .unwrap();
+        assert!(!format!("{error:?}").contains("hunter2"));
+    }
+}
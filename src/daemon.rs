@@ -0,0 +1,257 @@
+//! Syncing jobs on their schedules, staying logged in and keeping caches in memory
+//! between runs.
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use chrono::Local;
+use eyre::{WrapErr, bail};
+use futures::future::join_all;
+use tokio::{net::TcpListener, signal, sync::watch};
+use tracing::{Instrument, error, info, info_span, warn};
+
+use crate::{
+    Context, Session,
+    cache::{self, Lock},
+    config::Job,
+    overrides::Overrides,
+    prometheus,
+    reddit::{self, Reddit},
+    schedule::Schedule,
+    spotify::{self, Spotify},
+    tidal::{self, Tidal},
+    track::Track,
+};
+
+/// A job's services, logged in.
+pub struct Sessions {
+    spotify: Option<Session<Spotify>>,
+    tidal: Option<Session<Tidal>>,
+}
+
+impl Sessions {
+    /// Log in to each service there are settings for.
+    pub async fn new(
+        ctx: &Context,
+        spotify: Option<spotify::Settings>,
+        tidal: Option<tidal::Settings>,
+    ) -> eyre::Result<Self> {
+        let spotify = match spotify {
+            Some(settings) => Some(Session::new(ctx, settings).await?),
+            None => None,
+        };
+        let tidal = match tidal {
+            Some(settings) => Some(Session::new(ctx, settings).await?),
+            None => None,
+        };
+        Ok(Self { spotify, tidal })
+    }
+
+    /// Update every service's playlist with the given tracks.
+    pub async fn run(&self, tracks: &[Track]) {
+        // One after the other, since Tidal looks tracks up by the ISRCs Spotify finds.
+        if let Some(spotify) = &self.spotify {
+            spotify.run(tracks).await;
+        }
+        if let Some(tidal) = &self.tidal {
+            tidal.run(tracks).await;
+        }
+    }
+
+    /// Use these overrides from now on.
+    pub fn set_overrides(&self, overrides: &Overrides) {
+        if let Some(spotify) = &self.spotify {
+            spotify.set_overrides(overrides);
+        }
+        if let Some(tidal) = &self.tidal {
+            tidal.set_overrides(overrides);
+        }
+    }
+
+    /// Load the caches again if someone else has changed them.
+    pub fn reload(&self) -> eyre::Result<()> {
+        if let Some(spotify) = &self.spotify {
+            spotify.reload()?;
+        }
+        if let Some(tidal) = &self.tidal {
+            tidal.reload()?;
+        }
+        Ok(())
+    }
+}
+
+/// Sync every job on its schedule until told to stop, then let the runs that are going
+/// finish.
+pub async fn run(jobs: Vec<Job>, metrics_address: Option<SocketAddr>) -> eyre::Result<()> {
+    let metrics = match metrics_address {
+        Some(address) => {
+            let handle = prometheus::install()?;
+            let listener = TcpListener::bind(address)
+                .await
+                .wrap_err_with(|| format!("couldn't serve metrics on {address}"))?;
+            info!(%address, "serving metrics");
+            Some(prometheus::serve(listener, handle))
+        }
+        None => None,
+    };
+
+    let client = reqwest::Client::new();
+    let mut scheduled = Vec::new();
+    for job in jobs {
+        let span = info_span!("job", name = job.name());
+        scheduled.push(Scheduled::new(job, &client).instrument(span).await?);
+    }
+
+    let (stop, stopped) = watch::channel(());
+    let signal = async move {
+        let result = shutdown_signal().await;
+        info!("shutting down once the current runs are done");
+        let _ = stop.send(());
+        result
+    };
+    let runs = scheduled.iter().map(|job| {
+        let span = info_span!("job", name = job.name);
+        job.run_forever(stopped.clone()).instrument(span)
+    });
+    let runs = async { tokio::join!(signal, join_all(runs)).0 };
+    let Some(metrics) = metrics else {
+        return runs.await;
+    };
+    // The metrics are served until the runs are done.
+    tokio::select! {
+        result = runs => result,
+        result = metrics => result,
+    }
+}
+
+/// Resolves on SIGTERM or Ctrl-C.
+async fn shutdown_signal() -> eyre::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            _ = terminate.recv() => Ok(()),
+            result = signal::ctrl_c() => Ok(result?),
+        }
+    }
+    #[cfg(not(unix))]
+    Ok(signal::ctrl_c().await?)
+}
+
+/// What a job keeps between runs.
+struct Scheduled {
+    name: String,
+    schedule: Schedule,
+    source: reddit::Source,
+    reddit: Reddit,
+    sessions: Sessions,
+    cache: cache::Settings,
+    overrides_file: Option<PathBuf>,
+    /// When the overrides file had last been changed as of when we loaded it.
+    overrides_at: Mutex<Option<SystemTime>>,
+}
+
+impl Scheduled {
+    async fn new(job: Job, client: &reqwest::Client) -> eyre::Result<Self> {
+        let name = job.name().to_owned();
+        let settings = job.settings;
+        let schedule = Schedule::new(settings.interval, settings.cron.as_deref())?;
+
+        let cache_settings = settings.cache();
+        // Only held while the cache is loaded; each run takes it again.
+        let _lock = match &cache_settings.dir {
+            Some(dir) => match Lock::acquire_for_run(dir, cache_settings.wait_for_lock).await? {
+                Some(lock) => Some(lock),
+                None => bail!("another run is using the cache in {}", dir.display()),
+            },
+            None => None,
+        };
+        let overrides_file = settings.overrides_file();
+        let overrides_at = overrides_file.as_deref().and_then(modified);
+        let ctx = Context {
+            store: cache_settings.store()?,
+            ..settings.context(client)?
+        };
+
+        let reddit = Reddit::login(settings.reddit, client).await?;
+        let sessions = Sessions::new(&ctx, settings.spotify, settings.tidal).await?;
+
+        Ok(Self {
+            name,
+            schedule,
+            source: settings.source,
+            reddit,
+            sessions,
+            cache: cache_settings,
+            overrides_file,
+            overrides_at: Mutex::new(overrides_at),
+        })
+    }
+
+    async fn run_forever(&self, mut stopped: watch::Receiver<()>) {
+        let mut due = Local::now();
+        loop {
+            if let Err(error) = self.run().await {
+                error!("{error:?}");
+            }
+
+            let next = match self.schedule.next(due, Local::now()) {
+                Ok(next) => next,
+                Err(error) => {
+                    error!("{error:?}");
+                    return;
+                }
+            };
+            info!(%next, "waiting for the next run");
+            let wait = (next - Local::now()).to_std().unwrap_or_default();
+            tokio::select! {
+                () = tokio::time::sleep(wait) => due = next,
+                _ = stopped.changed() => return,
+            }
+        }
+    }
+
+    async fn run(&self) -> eyre::Result<()> {
+        info!("Beginning update");
+        // Let go of between runs, so that `review` and `cache` can use the cache.
+        let _lock = match &self.cache.dir {
+            Some(dir) => match Lock::acquire_for_run(dir, self.cache.wait_for_lock).await? {
+                Some(lock) => Some(lock),
+                None => {
+                    warn!(dir = %dir.display(), "another run is using the cache; skipping this one");
+                    return Ok(());
+                }
+            },
+            None => None,
+        };
+        self.reload()?;
+
+        let tracks = self.reddit.tracks(&self.source).await?;
+        self.sessions.run(&tracks).await;
+        Ok(())
+    }
+
+    /// Pick up whatever's been changed in the cache or the overrides file since the
+    /// last run.
+    fn reload(&self) -> eyre::Result<()> {
+        if let Some(path) = &self.overrides_file {
+            let mut overrides_at = self.overrides_at.lock().unwrap();
+            let modified = modified(path);
+            if modified != *overrides_at {
+                info!(path = %path.display(), "the overrides have changed; loading them again");
+                self.sessions.set_overrides(&Overrides::load(path)?);
+                *overrides_at = modified;
+            }
+        }
+        self.sessions.reload()
+    }
+}
+
+/// When the file was last changed; `None` if it doesn't exist.
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
@@ -1,26 +1,28 @@
 use tracing::Span;
 
-use crate::{Record, Service, cache::Cache, track::Track};
+use crate::{
+    Context, Record, Service,
+    cache::{Cache, Isrcs},
+    tokens::TokenStore,
+    track::Track,
+};
 
 pub struct Data<S: Service> {
     pub cache: Cache,
+    pub isrcs: Isrcs,
     pub client: reqwest::Client,
+    pub tokens: Option<TokenStore>,
     pub settings: S::Settings,
-    pub tracks: Vec<Track>,
 }
 
 impl<S: Service> Data<S> {
-    pub fn new(
-        cache: &Cache,
-        client: &reqwest::Client,
-        settings: S::Settings,
-        tracks: &[Track],
-    ) -> Self {
+    pub fn new(ctx: &Context, cache: &Cache, settings: S::Settings) -> Self {
         Self {
             cache: cache.clone(),
+            isrcs: ctx.isrcs.clone(),
+            client: ctx.client.clone(),
+            tokens: ctx.tokens.clone(),
             settings,
-            client: client.clone(),
-            tracks: tracks.to_owned(),
         }
     }
 
@@ -30,11 +32,12 @@ impl<S: Service> Data<S> {
         Fut: Future<Output = eyre::Result<Option<Record>>>,
     >(
         &'a self,
+        tracks: &'a [Track],
         search: F,
     ) -> Vec<Record> {
         let records = self
             .cache
-            .get_all(&self.tracks, search)
+            .get_all(S::NAME, tracks, search)
             .await
             .collect::<Vec<_>>();
 
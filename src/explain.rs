@@ -0,0 +1,120 @@
+//! Explaining how a single track would be matched, for when someone asks why it isn't
+//! in a playlist.
+
+use crate::{
+    Context, Record, Service,
+    cache::{Cache, Verdict},
+    data::Data,
+    overrides::Override,
+    track::Track,
+};
+
+/// Run every query the service would try for the track, and print what each one found
+/// and whether it would be accepted. Nothing is cached, and no playlist is touched.
+/// Overrides are reported, and decide the result as they would in a run, but the queries
+/// are run regardless.
+///
+/// An accepted record's ISRC is added to the context's ISRCs, so services explained later
+/// try the same lookup a run would.
+pub async fn explain<S: Service>(
+    ctx: &Context,
+    settings: S::Settings,
+    track: &Track,
+) -> eyre::Result<()> {
+    let Context {
+        isrcs, overrides, ..
+    } = ctx;
+    let data: Data<S> = Data::new(ctx, &Cache::default(), settings);
+    let service = S::new(data).await?;
+
+    println!("{}:", S::NAME);
+    let overridden = overrides.for_service(S::NAME).remove(track);
+    match &overridden {
+        Some(Override::Never) => println!("  override:  never added"),
+        Some(Override::Pin(id)) => println!("  override:  pinned to {id}"),
+        None => (),
+    }
+    let mut chosen = None;
+    for query in service.queries(track) {
+        println!("  query:     {query}");
+        let Some(record) = service.query(&query).await? else {
+            println!("    nothing found");
+            continue;
+        };
+
+        let Verdict { scores, rejected } = Verdict::new(&record, track, isrcs);
+        println!("    candidate: {record}");
+        if let Some(isrc) = &record.isrc {
+            println!("    isrc:      {isrc}");
+        }
+        println!(
+            "    scores:    title {:.2}, artist {:.2}",
+            scores.title, scores.artist
+        );
+        println!(
+            "    decision:  {}",
+            if rejected { "reject" } else { "accept" }
+        );
+        // A run stops at the first query that finds anything, good match or not.
+        chosen.get_or_insert((record, rejected));
+    }
+
+    if overridden.is_none()
+        && let Some((record, false)) = &chosen
+    {
+        isrcs.accept(track, record);
+    }
+    println!(
+        "  result:    {}",
+        outcome(overridden.as_ref(), chosen.as_ref())
+    );
+    Ok(())
+}
+
+/// What a run would do with the track, given what the queries found. An override wins
+/// over the search, as it does in [`Cache::with_cache`].
+fn outcome(overridden: Option<&Override>, chosen: Option<&(Record, bool)>) -> String {
+    match (overridden, chosen) {
+        (Some(Override::Never), _) => "never added, as overridden".to_owned(),
+        (Some(Override::Pin(id)), _) => format!("{id} would be added, as pinned"),
+        (None, Some((record, false))) => format!("{record} would be added"),
+        (None, Some((record, true))) => format!("{record} would be rejected"),
+        (None, None) => "not found".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Record, overrides::Override};
+
+    use super::outcome;
+
+    #[test]
+    fn test_outcome() {
+        let found = Record {
+            id: "aaa".into(),
+            title: "fife".into(),
+            artists: vec!["foo".into()],
+            isrc: None,
+        };
+        let accepted = (found.clone(), false);
+        let rejected = (found, true);
+
+        assert!(outcome(None, Some(&accepted)).ends_with("would be added"));
+        assert!(outcome(None, Some(&rejected)).ends_with("would be rejected"));
+        assert_eq!("not found", outcome(None, None));
+
+        // Overrides win, whatever the search found.
+        let never = Override::Never;
+        for chosen in [Some(&accepted), Some(&rejected), None] {
+            assert_eq!("never added, as overridden", outcome(Some(&never), chosen));
+        }
+        let pinned = Override::Pin("bbb".into());
+        for chosen in [Some(&accepted), Some(&rejected), None] {
+            assert_eq!(
+                "bbb would be added, as pinned",
+                outcome(Some(&pinned), chosen)
+            );
+        }
+    }
+}
@@ -0,0 +1,43 @@
+//! Just enough of an HTTP server to answer a browser or a scraper: read the request
+//! line, and send a whole plain text response.
+
+use std::time::Duration;
+
+use eyre::eyre;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+
+/// The longest request line we'll read, in bytes.
+const MAX_REQUEST_LINE: u64 = 8192;
+/// How long a client has to send its request line.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Read what the request is for, e.g. `/callback?code=...`; `None` if it doesn't say.
+/// We don't need the headers.
+///
+/// Bounded, so that a client that never finishes a line can't hold on to more than a
+/// little memory, or for long.
+pub(crate) async fn read_target(stream: TcpStream) -> eyre::Result<(TcpStream, Option<String>)> {
+    let mut stream = BufReader::new(stream).take(MAX_REQUEST_LINE);
+    // e.g. `GET /metrics HTTP/1.1`
+    let mut request_line = String::new();
+    timeout(REQUEST_TIMEOUT, stream.read_line(&mut request_line))
+        .await
+        .map_err(|_| eyre!("timed out reading the request"))??;
+    let target = request_line.split_whitespace().nth(1).map(str::to_owned);
+    Ok((stream.into_inner().into_inner(), target))
+}
+
+/// Send a whole plain text response, and close the connection.
+pub(crate) async fn respond(mut stream: TcpStream, status: &str, body: &str) -> eyre::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
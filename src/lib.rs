@@ -1,37 +1,86 @@
 use std::{
     fmt,
-    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Instant,
 };
 
-use cache::Cache;
+use cache::{
+    Cache, Expiry, Isrcs,
+    store::{Revision, Store},
+};
 use data::Data;
-use eyre::Context;
-use reqwest::{RequestBuilder, StatusCode};
+use eyre::Context as _;
+use metrics::gauge;
+use overrides::Overrides;
+use reqwest::{RequestBuilder, Response, StatusCode};
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
-use tracing::{error, field};
+use tokens::TokenStore;
+use tracing::{Instrument, debug_span, error, field, info};
 use track::Track;
 
+pub mod auth;
 pub mod cache;
+pub mod config;
+pub mod daemon;
 pub mod data;
+pub mod explain;
+mod http;
+pub mod maintenance;
+pub mod overrides;
+pub mod prometheus;
+pub mod redact;
 pub mod reddit;
+pub mod review;
+pub mod schedule;
 pub mod spotify;
+pub mod telemetry;
 pub mod tidal;
+pub mod tokens;
 pub mod track;
 
-#[derive(Deserialize)]
+#[derive(Clone, Serialize)]
 #[serde(transparent)]
 pub struct Secret<T>(T);
 
-impl<T> Secret<T> {
+impl<T: AsRef<str>> Secret<T> {
+    /// The value is masked in errors and logs from now on.
     pub fn new(s: T) -> Self {
+        redact::register(s.as_ref());
+        Self(s)
+    }
+
+    /// Like [`Secret::new`], but only masked until enough newer tokens have come along;
+    /// for the tokens services hand out as we go.
+    pub fn recent(s: T) -> Self {
+        redact::register_recent(s.as_ref());
         Self(s)
     }
+}
+
+/// For `deserialize_with`, for a [`Secret::recent`].
+fn recent<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Secret<String>, D::Error> {
+    String::deserialize(deserializer).map(Secret::recent)
+}
+
+/// For `deserialize_with`, for an optional [`Secret::recent`].
+fn recent_option<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Secret<String>>, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.map(Secret::recent))
+}
 
+impl<T> Secret<T> {
     pub fn expose_secret(&self) -> &T {
         &self.0
     }
 }
 
+impl<'de, T: Deserialize<'de> + AsRef<str>> Deserialize<'de> for Secret<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Self::new)
+    }
+}
+
 impl<T> fmt::Debug for Secret<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str("<SECRET>")
@@ -40,17 +89,41 @@ impl<T> fmt::Debug for Secret<T> {
 
 #[derive(Deserialize)]
 struct AuthResponse {
+    #[serde(deserialize_with = "recent")]
     access_token: Secret<String>,
+    /// Some services hand out a new refresh token when one is used, after which the old
+    /// one eventually stops working.
+    #[serde(default, deserialize_with = "recent_option")]
+    refresh_token: Option<Secret<String>>,
+    /// How many seconds the access token is good for.
+    #[serde(default)]
+    expires_in: Option<u64>,
 }
 
 /// A record, as the result of a service-specific search.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Record {
     /// Whatever the given service uses as an id. For tidal, this is a number.
     /// For spotify, it's a uri.
     id: String,
     title: String,
     artists: Vec<String>,
+    /// The International Standard Recording Code, which identifies a recording across
+    /// services.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    isrc: Option<String>,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "'{}' - '{}' ({})",
+            self.artists.join(", "),
+            self.title,
+            self.id
+        )
+    }
 }
 
 #[allow(async_fn_in_trait)]
@@ -59,66 +132,178 @@ pub trait Service: Sized {
     type Settings;
 
     async fn new(data: Data<Self>) -> eyre::Result<Self>;
-    async fn run(&self) -> eyre::Result<()>;
+    fn data(&self) -> &Data<Self>;
+    /// The queries to try for a track, best first.
+    fn queries(&self, track: &Track) -> Vec<Query>;
+    async fn query(&self, query: &Query) -> eyre::Result<Option<Record>>;
+
+    /// Search for a single track, without going through the cache. The first query
+    /// that finds anything wins.
+    async fn search(&self, track: &Track) -> eyre::Result<Option<Record>> {
+        for query in self.queries(track) {
+            if let Some(record) = self.query(&query).await? {
+                return Ok(Some(record));
+            }
+        }
+        Ok(None)
+    }
+    /// The id of the playlist that's updated.
+    fn playlist_id(&self) -> &str;
+    /// Replace the contents of the playlist with the given records.
+    async fn update_playlist(&self, records: Vec<Record>) -> eyre::Result<()>;
 }
-#[tracing::instrument(skip_all, fields(service = S::NAME, found = field::Empty, cache_hits = field::Empty, rejected = field::Empty))]
-pub async fn run<S: Service>(
-    cache_dir: Option<PathBuf>,
-    settings: S::Settings,
-    tracks: Vec<Track>,
-    client: reqwest::Client,
-) {
-    fn load_cache(path: Option<&Path>) -> eyre::Result<Cache> {
-        let Some(p) = path else {
-            return Ok(Cache::default());
-        };
-        let cache = serde_json::from_str(&std::fs::read_to_string(p)?)?;
-        Ok(cache)
+
+/// One way of searching a service for a track.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    /// Look the recording up by the ISRC another service matched it to.
+    Isrc(String),
+    /// A free text search, in whatever syntax the service understands.
+    Text(String),
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Query::Isrc(isrc) => write!(f, "isrc {isrc}"),
+            Query::Text(text) => write!(f, "text '{text}'"),
+        }
     }
+}
 
-    fn save_cache(path: &Path, cache: &Cache) -> eyre::Result<()> {
-        let ser = serde_json::to_string(&cache)?;
-        std::fs::create_dir_all(path.parent().unwrap())?;
-        std::fs::write(path, ser)?;
-        Ok(())
+/// Everything that's shared between services.
+#[derive(Clone, Default)]
+pub struct Context {
+    pub client: reqwest::Client,
+    /// Where caches are kept between runs; they're only kept in memory if not set.
+    pub store: Option<Arc<dyn Store>>,
+    pub expiry: Expiry,
+    pub overrides: Overrides,
+    pub isrcs: Isrcs,
+    /// Where credentials are kept between runs; refresh tokens the services rotate are
+    /// lost if not set.
+    pub tokens: Option<TokenStore>,
+}
+
+impl Context {
+    fn cache<S: Service>(&self, tracks: &[Track]) -> Cache {
+        let loaded = match &self.store {
+            Some(store) => store.load(S::NAME),
+            None => Ok(Cache::default()),
+        };
+        let cache: Cache = match loaded {
+            Ok(c) => c,
+            Err(error) => {
+                error!(%error, "Failed to load cache");
+                Cache::default()
+            }
+        };
+        let cache = cache
+            .with_overrides(self.overrides.for_service(S::NAME))
+            .with_expiry(self.expiry)
+            .with_isrcs(self.isrcs.clone());
+        cache.trim(tracks);
+        cache
     }
+}
 
-    let cache_path = cache_dir.map(|mut dir| {
-        dir.push(format!("{}.json", S::NAME));
-        dir
-    });
+/// A service that's kept between runs, so that running on a schedule doesn't log in and
+/// load the cache again every time.
+pub struct Session<S: Service> {
+    service: S,
+    cache: Cache,
+    store: Option<Arc<dyn Store>>,
+    /// The stored cache's revision as of when we last loaded or saved it, so that changes
+    /// made by anyone else (e.g. `review`) are picked up.
+    revision: Mutex<Option<Revision>>,
+}
 
-    let cache: Cache = match load_cache(cache_path.as_deref()) {
-        Ok(c) => {
-            c.trim(&tracks);
-            c
-        }
-        Err(error) => {
-            error!(%error, "Failed to load cache");
-            Cache::default()
-        }
-    };
-    let data: Data<S> = Data::new(&cache, &client, settings, &tracks);
-    let client = match S::new(data).await {
-        Ok(client) => client,
-        Err(error) => {
-            error!(%error, "failed to create client");
-            return;
+impl<S: Service> Session<S> {
+    pub async fn new(ctx: &Context, settings: S::Settings) -> eyre::Result<Self> {
+        let revision = ctx.store.as_ref().and_then(|store| store.revision(S::NAME));
+        let cache = ctx.cache::<S>(&[]);
+        let data: Data<S> = Data::new(ctx, &cache, settings);
+        Ok(Self {
+            service: S::new(data).await?,
+            cache,
+            store: ctx.store.clone(),
+            revision: Mutex::new(revision),
+        })
+    }
+
+    /// Load the cache again if someone else has changed it since we last did.
+    pub fn reload(&self) -> eyre::Result<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        let mut revision = self.revision.lock().unwrap();
+        let latest = store.revision(S::NAME);
+        if latest == *revision {
+            return Ok(());
         }
-    };
+        info!(service = S::NAME, "the cache has changed; loading it again");
+        self.cache.replace_entries(store.load(S::NAME)?);
+        *revision = latest;
+        Ok(())
+    }
 
-    if let Err(error) = client.run().await {
-        error!(%error, "failed to update playlist");
+    /// Use these overrides from now on.
+    pub fn set_overrides(&self, overrides: &Overrides) {
+        self.cache.replace_overrides(overrides.for_service(S::NAME));
     }
 
-    if let Some(path) = cache_path {
-        cache.trim(&tracks);
-        if let Err(error) = save_cache(&path, &cache) {
-            error!(%error, "failed to save cache");
+    /// Update the service's playlist with the given tracks.
+    #[tracing::instrument(skip_all, fields(service = S::NAME, playlist = self.service.playlist_id(), found = field::Empty, cache_hits = field::Empty, overridden = field::Empty, rejected = field::Empty))]
+    pub async fn run(&self, tracks: &[Track]) {
+        self.cache.trim(tracks);
+        let records = self
+            .service
+            .data()
+            .search_all(tracks, |t| self.service.search(t))
+            .await;
+        match self.service.update_playlist(records).await {
+            Ok(()) => {
+                let now = cache::now() as f64;
+                gauge!("playlister_last_sync_timestamp_seconds", "service" => S::NAME, "playlist" => self.service.playlist_id().to_owned()).set(now);
+            }
+            Err(error) => error!(%error, "failed to update playlist"),
+        }
+
+        if let Some(store) = &self.store {
+            match store.save(S::NAME, &self.cache) {
+                Ok(()) => *self.revision.lock().unwrap() = store.revision(S::NAME),
+                Err(error) => error!(%error, "failed to save cache"),
+            }
         }
     }
 }
 
+/// What the service's playlist would contain after a run, without changing it or
+/// saving the cache.
+#[tracing::instrument(skip_all, fields(service = S::NAME, found = field::Empty, cache_hits = field::Empty, overridden = field::Empty, rejected = field::Empty))]
+pub async fn plan<S: Service>(
+    ctx: &Context,
+    settings: S::Settings,
+    tracks: &[Track],
+) -> eyre::Result<Vec<Record>> {
+    let cache = ctx.cache::<S>(tracks);
+    let data: Data<S> = Data::new(ctx, &cache, settings);
+    let client = S::new(data).await?;
+    Ok(client.data().search_all(tracks, |t| client.search(t)).await)
+}
+
+/// Search the service for a single track, without touching the cache.
+pub async fn search<S: Service>(
+    ctx: &Context,
+    settings: S::Settings,
+    track: &Track,
+) -> eyre::Result<Option<Record>> {
+    let data: Data<S> = Data::new(ctx, &Cache::default(), settings);
+    S::new(data).await?.search(track).await
+}
+
+/// A request that failed. The URL and response are redacted, as either could have
+/// credentials in them.
 #[derive(Debug)]
 struct RequestError {
     #[allow(dead_code)]
@@ -127,8 +312,25 @@ struct RequestError {
     url: String,
     #[allow(dead_code)]
     status: StatusCode,
+    /// Left out when it may have credentials in it.
     #[allow(dead_code)]
-    body: String,
+    body: Option<String>,
+}
+
+impl RequestError {
+    fn new(
+        msg: impl Into<String>,
+        url: &reqwest::Url,
+        status: StatusCode,
+        body: Option<&[u8]>,
+    ) -> Self {
+        Self {
+            msg: msg.into(),
+            url: redact::redact(url.as_str()),
+            status,
+            body: body.map(|body| redact::redact(&String::from_utf8_lossy(body))),
+        }
+    }
 }
 
 impl std::error::Error for RequestError {}
@@ -140,62 +342,105 @@ impl fmt::Display for RequestError {
 }
 
 #[allow(async_fn_in_trait)]
-pub trait JsonRequest {
-    async fn send_it(self) -> eyre::Result<()>;
-    async fn send_it_json<T: DeserializeOwned>(self) -> eyre::Result<T>;
-}
+pub trait JsonRequest: Sized {
+    async fn send_request(self) -> eyre::Result<Response>;
 
-impl JsonRequest for RequestBuilder {
     async fn send_it(self) -> eyre::Result<()> {
-        let response = self.send().await?;
+        let response = self.send_request().await?;
 
-        let url = response.url().to_string();
+        let url = response.url().clone();
         let status = response.status();
         let full = response.bytes().await?;
 
         if status.is_success() {
             Ok(())
         } else {
-            let body = String::from_utf8_lossy(&full).into_owned();
-            Err(RequestError {
-                msg: "request failed".to_string(),
-                url,
-                status,
-                body,
-            }
-            .into())
+            Err(RequestError::new("request failed", &url, status, Some(&full)).into())
         }
     }
+
     async fn send_it_json<T: DeserializeOwned>(self) -> eyre::Result<T> {
-        let response = self.send().await?;
+        receive(self.send_request().await?, false).await
+    }
 
-        let url = response.url().to_string();
-        let status = response.status();
-        let full = response.bytes().await?;
+    /// Like `send_it_json`, for a response with credentials in it, such as a new access
+    /// token.
+    async fn send_it_secret_json<T: DeserializeOwned>(self) -> eyre::Result<T> {
+        receive(self.send_request().await?, true).await
+    }
+}
 
-        if status.is_success() {
-            match serde_json::from_slice(&full) {
-                Ok(parsed) => Ok(parsed),
-                Err(error) => {
-                    let body = String::from_utf8_lossy(&full).into_owned();
-                    let msg = "Failed to parse JSON response".to_string();
-                    Err(error).wrap_err(RequestError {
-                        msg,
-                        url,
-                        status,
-                        body,
-                    })
-                }
-            }
-        } else {
-            let body = String::from_utf8_lossy(&full).into_owned();
-            Err(RequestError {
-                msg: "request failed".to_string(),
-                url,
-                status,
-                body,
+/// Check that a request succeeded, and parse the response. A secret response is left
+/// out of any error, along with whatever parsing it would quote from it; a failed
+/// request's response is still kept, since that says why it failed rather than having
+/// credentials in it.
+pub(crate) async fn receive<T: DeserializeOwned>(
+    response: Response,
+    secret: bool,
+) -> eyre::Result<T> {
+    let url = response.url().clone();
+    let status = response.status();
+    let full = response.bytes().await?;
+
+    if !status.is_success() {
+        return Err(RequestError::new("request failed", &url, status, Some(&full)).into());
+    }
+
+    match serde_json::from_slice(&full) {
+        Ok(parsed) => Ok(parsed),
+        Err(error) if secret => {
+            let msg = format!(
+                "Failed to parse JSON response at line {} column {}",
+                error.line(),
+                error.column()
+            );
+            Err(RequestError::new(msg, &url, status, None).into())
+        }
+        Err(error) => {
+            let msg = "Failed to parse JSON response";
+            Err(error).wrap_err(RequestError::new(msg, &url, status, Some(&full)))
+        }
+    }
+}
+
+impl JsonRequest for RequestBuilder {
+    async fn send_request(self) -> eyre::Result<Response> {
+        send(self, 0).await
+    }
+}
+
+/// Send a request in a span of its own, and record how it went. `resends` is how many
+/// times it's been sent before.
+pub(crate) async fn send(builder: RequestBuilder, resends: u32) -> eyre::Result<Response> {
+    let (client, request) = builder.build_split();
+    let request = request?;
+    let method = request.method().clone();
+    let template = prometheus::endpoint(request.url());
+    let span = debug_span!(
+        "http",
+        otel.name = format!("{method} {template}"),
+        otel.kind = "client",
+        otel.status_code = field::Empty,
+        http.request.method = %method,
+        url.template = template,
+        http.request.resend_count = resends,
+        http.response.status_code = field::Empty,
+    );
+
+    let started = Instant::now();
+    let result = client.execute(request).instrument(span.clone()).await;
+    prometheus::request(&result, started.elapsed());
+
+    match &result {
+        Ok(response) => {
+            span.record("http.response.status_code", response.status().as_u16());
+            if !response.status().is_success() {
+                span.record("otel.status_code", "ERROR");
             }
-            .into())
+        }
+        Err(_) => {
+            span.record("otel.status_code", "ERROR");
         }
     }
+    Ok(result?)
 }
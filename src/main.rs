@@ -1,92 +1,616 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{path::PathBuf, process, sync::Arc};
 
-use config::Environment;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use eyre::{OptionExt, WrapErr, bail};
 use playlister::{
-    reddit,
-    spotify::{self, Spotify},
-    tidal::{self, Tidal},
+    Context, Record, Service, auth,
+    cache::{Lock, store::Store},
+    config::{Global, LogFormat, Settings, Sources, select_one},
+    daemon::{self, Sessions},
+    explain, maintenance, redact,
+    reddit::{self, Reddit},
+    review,
+    spotify::Spotify,
+    telemetry::Telemetry,
+    tidal::Tidal,
+    track::Track,
 };
-use serde::{Deserialize, Deserializer};
-use tokio::{runtime, task::JoinSet};
-use tracing::{Level, field, info, info_span};
-use tracing_subscriber::fmt::format::FmtSpan;
-
-#[derive(Deserialize, Debug)]
-pub struct Settings {
-    #[serde(default = "info", deserialize_with = "de_level")]
-    log_level: Level,
+use tokio::runtime;
+use tracing::{Instrument, Level, error, info, info_span, warn};
+use tracing_subscriber::{
+    EnvFilter, Layer, filter::Targets, fmt::format::FmtSpan, layer::SubscriberExt,
+    util::SubscriberInitExt,
+};
+
+/// Keep playlists up to date with the posts on r/listentothis.
+///
+/// Settings are read from a config file if there is one, then the environment (and a
+/// .env file); the global flags take precedence over both.
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Cli {
+    #[command(flatten)]
+    global: GlobalArgs,
+    /// What to do; `sync` if not given.
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Args, Debug)]
+struct GlobalArgs {
+    /// Overrides LOG_LEVEL.
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+    /// Overrides CACHE_DIR.
+    #[arg(long, global = true)]
     cache_dir: Option<PathBuf>,
-    reddit: reddit::Settings,
-    spotify: Option<spotify::Settings>,
-    tidal: Option<tidal::Settings>,
+    /// Overrides CACHE_BACKEND.
+    #[arg(long, global = true)]
+    cache_backend: Option<String>,
+    /// Overrides OVERRIDES_FILE.
+    #[arg(long, global = true)]
+    overrides_file: Option<PathBuf>,
+    /// Overrides STATE_DIR.
+    #[arg(long, global = true)]
+    state_dir: Option<PathBuf>,
+    /// Overrides CONFIG_FILE.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Only use this job from the config file.
+    #[arg(long, global = true)]
+    job: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Update every configured playlist with the posts on r/listentothis, for every
+    /// job.
+    Sync {
+        /// Only update the playlists for these services.
+        #[arg(long)]
+        service: Vec<ServiceName>,
+    },
+    /// Keep syncing every job on its schedule, until stopped with SIGTERM or Ctrl-C.
+    ///
+    /// Each job is synced once at startup, and then whenever its `interval` or `cron`
+    /// says. Logins and caches are kept between runs.
+    Daemon,
+    /// Print the tracks parsed from r/listentothis.
+    Scrape,
+    /// Search for a single track, without touching the cache or any playlists.
+    Search {
+        artist: String,
+        title: String,
+        /// Only search this service.
+        #[arg(long)]
+        service: Option<ServiceName>,
+    },
+    /// Show how a track would be matched: every query tried, what it found, how well it
+    /// scored, and whether it would be accepted.
+    ///
+    /// Give either an artist and title, or a whole post title with `--post`. Nothing is
+    /// cached and no playlist is changed.
+    Explain {
+        #[arg(required_unless_present = "post")]
+        artist: Option<String>,
+        #[arg(required_unless_present = "post")]
+        title: Option<String>,
+        /// A post title, as it appears on r/listentothis.
+        #[arg(long, conflicts_with_all = ["artist", "title"])]
+        post: Option<String>,
+        /// Only explain the match for this service.
+        #[arg(long)]
+        service: Option<ServiceName>,
+    },
+    /// Print what each playlist would contain after a sync, without changing anything.
+    Plan {
+        /// Only plan the playlists for these services.
+        #[arg(long)]
+        service: Vec<ServiceName>,
+    },
+    /// Go through search results that were rejected as bad matches.
+    ///
+    /// Approving one pins it in the overrides file; confirming one means it won't
+    /// be asked about again.
+    Review {
+        /// Only review rejections for this service.
+        #[arg(long)]
+        service: Option<ServiceName>,
+        /// Just print the rejections, without asking about them.
+        #[arg(long)]
+        list: bool,
+    },
+    /// Log in to a service, and store the credentials a sync needs.
+    Auth {
+        #[command(subcommand)]
+        command: AuthCommand,
+    },
+    /// Inspect and maintain the search cache.
+    Cache {
+        /// Only look at the cache for this service.
+        #[arg(long, global = true)]
+        service: Option<ServiceName>,
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommand {
+    /// Print every cached track.
+    List,
+    /// Print cached tracks whose artist or title contains the query.
+    Search { query: String },
+    /// Count the tracks that were found, not found, and rejected.
+    Stats,
+    /// Forget a track, so it's searched for again on the next run.
+    Evict { artist: String, title: String },
+    /// Forget every track that wasn't found or was rejected.
+    EvictNegatives,
+    /// Write a service's cache to a JSON file. Needs `--service`.
+    Export { path: PathBuf },
+    /// Merge a JSON cache file, of any version, into a service's cache. Needs `--service`.
+    Import { path: PathBuf },
+}
+
+#[derive(Subcommand, Debug)]
+enum AuthCommand {
+    /// Log in to Spotify in a browser, and store the refresh token.
+    ///
+    /// Needs SPOTIFY__CLIENT_ID and SPOTIFY__CLIENT_SECRET, and
+    /// `http://127.0.0.1:<port>/callback` as a redirect URI of the Spotify app.
+    Spotify {
+        /// The local port to listen on for the redirect back from Spotify.
+        #[arg(long, default_value_t = 8082)]
+        port: u16,
+    },
+    /// Log in to Tidal on any device, and store the client and refresh token.
+    ///
+    /// Needs TIDAL__CLIENT_ID and TIDAL__CLIENT_SECRET, which can be given in a file or
+    /// by a command like any other secret.
+    Tidal,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ServiceName {
+    Spotify,
+    Tidal,
+}
+
+impl ServiceName {
+    const ALL: [ServiceName; 2] = [ServiceName::Spotify, ServiceName::Tidal];
+
+    fn name(self) -> &'static str {
+        match self {
+            ServiceName::Spotify => Spotify::NAME,
+            ServiceName::Tidal => Tidal::NAME,
+        }
+    }
+
+    /// The services that were asked for, or all of them if none were, in the order
+    /// they're synced in: services that find ISRCs before the ones that search by them.
+    fn selected(services: &[ServiceName]) -> Vec<ServiceName> {
+        Self::ALL
+            .into_iter()
+            .filter(|service| services.is_empty() || services.contains(service))
+            .collect()
+    }
+
+    fn names(services: &[ServiceName]) -> Vec<&'static str> {
+        Self::selected(services)
+            .into_iter()
+            .map(ServiceName::name)
+            .collect()
+    }
 }
 
-fn info() -> Level {
-    Level::INFO
+/// The sections a command needs: Reddit's if it scrapes, and those of the services it
+/// uses.
+fn sections(reddit: bool, services: &[ServiceName]) -> Vec<&'static str> {
+    let reddit = reddit.then_some("reddit");
+    reddit
+        .into_iter()
+        .chain(ServiceName::names(services))
+        .collect()
 }
 
-fn de_level<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Level, D::Error> {
-    let s = String::deserialize(deserializer)?;
-    Level::from_str(&s).map_err(serde::de::Error::custom)
+fn main() -> process::ExitCode {
+    match run() {
+        Ok(()) => process::ExitCode::SUCCESS,
+        // Reported redacted, as logs are; an error can quote a URL or a response.
+        Err(error) => {
+            eprintln!("Error: {}", redact::redact(&format!("{error:?}")));
+            process::ExitCode::FAILURE
+        }
+    }
 }
 
-fn main() -> eyre::Result<()> {
+fn run() -> eyre::Result<()> {
     color_eyre::install()?;
+    let cli = Cli::parse();
     let _ = dotenv::dotenv();
 
-    let config = config::Config::builder()
-        .add_source(Environment::default().separator("__"))
-        .build()?;
-    let settings: Settings = config.try_deserialize()?;
+    let job = cli.global.job.clone();
+    let job = job.as_deref();
+    let GlobalArgs {
+        log_level,
+        cache_dir,
+        cache_backend,
+        overrides_file,
+        state_dir,
+        config,
+        job: _,
+    } = cli.global;
+    let path = |path: Option<PathBuf>| path.map(|p| p.to_string_lossy().into_owned());
+    let flags = vec![
+        ("log_level", log_level),
+        ("cache_dir", path(cache_dir)),
+        ("cache_backend", cache_backend),
+        ("overrides_file", path(overrides_file)),
+        ("state_dir", path(state_dir)),
+    ];
+    let sources = Sources::new(config, flags)?;
+    let config = sources.top()?;
+    let global = config.clone().try_deserialize::<Global>()?;
 
-    tracing_subscriber::fmt()
-        .with_max_level(settings.log_level)
-        .with_span_events(FmtSpan::CLOSE)
-        .init();
+    // Dropped after the runtime, so that spans from the last requests are exported.
+    let telemetry = global
+        .otlp_endpoint
+        .as_deref()
+        .map(Telemetry::new)
+        .transpose()?;
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_writer(redact::Writer)
+        .with_span_events(FmtSpan::CLOSE);
+    let fmt = match global.log_format {
+        LogFormat::Full => fmt.boxed(),
+        LogFormat::Compact => fmt.compact().boxed(),
+        LogFormat::Pretty => fmt.pretty().boxed(),
+        LogFormat::Json => fmt
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    };
+    let filter = EnvFilter::try_new(&global.log_level)
+        .wrap_err_with(|| format!("invalid log level {:?}", global.log_level))?;
+    let fmt = fmt.with_filter(filter);
+    // Requests are debug spans, and are worth tracing whatever gets logged.
+    let otel = telemetry.as_ref().map(|telemetry| {
+        let targets = Targets::new()
+            .with_target("playlister", Level::DEBUG)
+            .with_default(Level::INFO);
+        telemetry.layer().with_filter(targets)
+    });
+    tracing_subscriber::registry().with(fmt).with(otel).init();
 
     let rt = runtime::Builder::new_current_thread()
         .enable_all()
         .build()?;
-    rt.block_on(run(settings))?;
+
+    let command = cli.command.unwrap_or(Command::Sync {
+        service: Vec::new(),
+    });
+    match command {
+        Command::Sync { service } => {
+            let jobs = sources.jobs(job, &sections(true, &service))?;
+            let count = jobs.len();
+            let mut failed = Vec::new();
+            for job in jobs {
+                let name = job.name().to_owned();
+                let span = info_span!("job", name);
+                let result = rt.block_on(sync(job.settings, &service).instrument(span));
+                // One job failing shouldn't hold up the others.
+                match result {
+                    Ok(()) => (),
+                    Err(error) if count == 1 => return Err(error),
+                    Err(error) => {
+                        error!(job = name, "{error:?}");
+                        failed.push(name);
+                    }
+                }
+            }
+            if !failed.is_empty() {
+                bail!("some jobs failed: {}", failed.join(", "));
+            }
+        }
+        Command::Daemon => {
+            let jobs = sources.jobs(job, &sections(true, &[]))?;
+            rt.block_on(daemon::run(jobs, global.metrics_address))?
+        }
+        Command::Scrape => {
+            let settings = select_one(sources.jobs(job, &["reddit"])?)?.settings;
+            let tracks = rt.block_on(scrape(
+                settings.reddit,
+                &settings.source,
+                &reqwest::Client::new(),
+            ))?;
+            for track in tracks {
+                println!("{track}");
+            }
+        }
+        Command::Search {
+            artist,
+            title,
+            service,
+        } => {
+            let sections = sections(false, service.as_slice());
+            let settings = select_one(sources.jobs(job, &sections)?)?.settings;
+            let track = Track::new(artist, title);
+            rt.block_on(search(settings, &track, service.as_slice()))?
+        }
+        Command::Explain {
+            artist,
+            title,
+            post,
+            service,
+        } => {
+            let sections = sections(false, service.as_slice());
+            let settings = select_one(sources.jobs(job, &sections)?)?.settings;
+            let track = match post {
+                Some(post) => {
+                    let track = reddit::parse_title(&settings.source.regex()?, &post);
+                    match track {
+                        Some(track) => {
+                            println!("parsed:    {track}");
+                            track
+                        }
+                        None => bail!("the title regex doesn't match {post:?}"),
+                    }
+                }
+                // clap makes sure both are there without a post.
+                None => Track::new(artist.unwrap(), title.unwrap()),
+            };
+            rt.block_on(explain(settings, &track, service.as_slice()))?
+        }
+        Command::Plan { service } => {
+            let jobs = sources.jobs(job, &sections(true, &service))?;
+            let named = jobs.iter().any(|job| job.name.is_some());
+            for job in jobs {
+                if named {
+                    println!("job {}:", job.name());
+                }
+                rt.block_on(plan(job.settings, &service))?;
+            }
+        }
+        Command::Review { service, list } => {
+            let settings = select_one(sources.jobs(job, &[])?)?.settings;
+            let (_lock, store) = open_store(&settings)?;
+            let overrides_file = settings.overrides_file().unwrap();
+            let services = ServiceName::names(service.as_slice());
+            review::review(&*store, &overrides_file, &services, list)?;
+        }
+        Command::Auth { command } => {
+            // Credentials are shared by every job, so logging in only needs one for a
+            // job that has its own.
+            let sections: &[&str] = match &command {
+                AuthCommand::Spotify { .. } => &["spotify"],
+                AuthCommand::Tidal => &["tidal"],
+            };
+            let (config, tokens) = match job {
+                Some(_) => {
+                    let job = select_one(sources.jobs(job, sections)?)?;
+                    let tokens = job.settings.tokens();
+                    (job.config, tokens)
+                }
+                None => (sources.resolve(config, &[], sections)?, global.tokens()),
+            };
+            let tokens =
+                tokens.ok_or_eyre("STATE_DIR or CACHE_DIR must be set to store credentials")?;
+            let client = reqwest::Client::new();
+            match command {
+                AuthCommand::Spotify { port } => {
+                    let app: auth::AppSettings = config
+                        .get("spotify")
+                        .wrap_err("SPOTIFY__CLIENT_ID and SPOTIFY__CLIENT_SECRET must be set")?;
+                    rt.block_on(auth::spotify(&client, &app, port, &tokens))?
+                }
+                AuthCommand::Tidal => {
+                    let app: auth::AppSettings = config
+                        .get("tidal")
+                        .wrap_err("TIDAL__CLIENT_ID and TIDAL__CLIENT_SECRET must be set")?;
+                    rt.block_on(auth::tidal(&client, &app, &tokens))?
+                }
+            }
+        }
+        Command::Cache { service, command } => {
+            let settings = select_one(sources.jobs(job, &[])?)?.settings;
+            let (_lock, store) = open_store(&settings)?;
+            let services = ServiceName::names(service.as_slice());
+            match command {
+                CacheCommand::List => maintenance::list(&*store, &services, None)?,
+                CacheCommand::Search { query } => {
+                    maintenance::list(&*store, &services, Some(&query))?
+                }
+                CacheCommand::Stats => maintenance::stats(&*store, &services)?,
+                CacheCommand::Evict { artist, title } => {
+                    maintenance::evict(&*store, &services, &Track::new(artist, title))?
+                }
+                CacheCommand::EvictNegatives => maintenance::evict_negatives(&*store, &services)?,
+                CacheCommand::Export { path } => {
+                    let service = service.ok_or_eyre("export needs a --service")?;
+                    maintenance::export(&*store, service.name(), &path)?
+                }
+                CacheCommand::Import { path } => {
+                    let service = service.ok_or_eyre("import needs a --service")?;
+                    maintenance::import(&*store, service.name(), &path)?
+                }
+            }
+        }
+    }
 
     Ok(())
 }
 
+/// Open the cache store for a command that reads or changes it outside of a run.
+fn open_store(settings: &Settings) -> eyre::Result<(Lock, Arc<dyn Store>)> {
+    let cache_dir = settings
+        .cache_dir
+        .as_deref()
+        .ok_or_eyre("CACHE_DIR must be set to use the cache")?;
+    let Some(lock) = Lock::try_acquire(cache_dir)? else {
+        bail!(
+            "another run is using the cache in {}; try again once it's done",
+            cache_dir.display()
+        );
+    };
+    let store = settings.cache().store()?.unwrap();
+    Ok((lock, store))
+}
+
+/// The tracks currently posted to a subreddit.
+async fn scrape(
+    settings: Option<reddit::Settings>,
+    source: &reddit::Source,
+    client: &reqwest::Client,
+) -> eyre::Result<Vec<Track>> {
+    Reddit::login(settings, client).await?.tracks(source).await
+}
+
 #[tracing::instrument(skip(settings))]
-async fn run(settings: Settings) -> eyre::Result<()> {
+async fn sync(settings: Settings, services: &[ServiceName]) -> eyre::Result<()> {
     info!("Beginning update");
 
-    let listentothis_regex = regex::Regex::new(r"(.*?)\s+[-–—\s]+\s+(.*?)\s*[\(\[]")?;
-
     let client = reqwest::Client::new();
 
-    let tracks = {
-        let span = info_span!("reddit", count = field::Empty);
-        let _enter = span.enter();
-        let tracks = reddit::Reddit::new(settings.reddit, client.clone())
-            .await?
-            .tracks("r/listentothis", listentothis_regex)
-            .await?;
-        span.record("count", tracks.len());
-        tracks
+    let cache_settings = settings.cache();
+    // Held until every service is done with its cache.
+    let _lock = match &cache_settings.dir {
+        Some(dir) => match Lock::acquire_for_run(dir, cache_settings.wait_for_lock).await? {
+            Some(lock) => Some(lock),
+            None => {
+                warn!(dir = %dir.display(), "another run is using the cache; skipping this one");
+                return Ok(());
+            }
+        },
+        None => None,
     };
 
-    let mut set = JoinSet::new();
+    let ctx = Context {
+        store: cache_settings.store()?,
+        ..settings.context(&client)?
+    };
+    let tracks = scrape(settings.reddit, &settings.source, &client).await?;
+    let services = ServiceName::selected(services);
+    let spotify = settings
+        .spotify
+        .filter(|_| services.contains(&ServiceName::Spotify));
+    let tidal = settings
+        .tidal
+        .filter(|_| services.contains(&ServiceName::Tidal));
+    Sessions::new(&ctx, spotify, tidal)
+        .await?
+        .run(&tracks)
+        .await;
+    Ok(())
+}
 
-    if let Some(spotify_settings) = settings.spotify {
-        let fut = playlister::run::<Spotify>(
-            settings.cache_dir.clone(),
-            spotify_settings,
-            tracks.clone(),
-            client.clone(),
-        );
-        set.spawn(fut);
+/// Print what each playlist would contain after a sync. The cache is only read, but
+/// opening it can still set it up or set aside a corrupt one, so this shares the lock
+/// with other readers.
+async fn plan(settings: Settings, services: &[ServiceName]) -> eyre::Result<()> {
+    let client = reqwest::Client::new();
+    let _lock = match &settings.cache_dir {
+        Some(dir) => match Lock::try_acquire_shared(dir)? {
+            Some(lock) => Some(lock),
+            None => bail!(
+                "another run is using the cache in {}; try again once it's done",
+                dir.display()
+            ),
+        },
+        None => None,
+    };
+    let ctx = Context {
+        store: settings.cache().store()?,
+        ..settings.context(&client)?
+    };
+    let tracks = scrape(settings.reddit, &settings.source, &client).await?;
+    let services = ServiceName::selected(services);
+
+    if let Some(spotify_settings) = settings.spotify
+        && services.contains(&ServiceName::Spotify)
+    {
+        let records = playlister::plan::<Spotify>(&ctx, spotify_settings, &tracks).await?;
+        print_plan(Spotify::NAME, &records);
     }
-    if let Some(tidal_settings) = settings.tidal {
-        let fut = playlister::run::<Tidal>(settings.cache_dir, tidal_settings, tracks, client);
-        set.spawn(fut);
+    if let Some(tidal_settings) = settings.tidal
+        && services.contains(&ServiceName::Tidal)
+    {
+        let records = playlister::plan::<Tidal>(&ctx, tidal_settings, &tracks).await?;
+        print_plan(Tidal::NAME, &records);
     }
+    Ok(())
+}
+
+fn print_plan(service: &str, records: &[Record]) {
+    println!("{service}: {} tracks", records.len());
+    for record in records {
+        println!("  {record}");
+    }
+}
+
+async fn search(settings: Settings, track: &Track, services: &[ServiceName]) -> eyre::Result<()> {
+    let ctx = settings.context(&reqwest::Client::new())?;
+    let services = ServiceName::selected(services);
 
-    set.join_all().await;
+    if let Some(spotify_settings) = settings.spotify
+        && services.contains(&ServiceName::Spotify)
+    {
+        let record = playlister::search::<Spotify>(&ctx, spotify_settings, track).await?;
+        print_search(Spotify::NAME, record);
+    }
+    if let Some(tidal_settings) = settings.tidal
+        && services.contains(&ServiceName::Tidal)
+    {
+        let record = playlister::search::<Tidal>(&ctx, tidal_settings, track).await?;
+        print_search(Tidal::NAME, record);
+    }
     Ok(())
 }
+
+fn print_search(service: &str, record: Option<Record>) {
+    match record {
+        Some(record) => println!("{service}: {record}"),
+        None => println!("{service}: not found"),
+    }
+}
+
+/// Explain the services one at a time, so that later ones get the ISRCs earlier ones
+/// matched, like they would in a run.
+async fn explain(settings: Settings, track: &Track, services: &[ServiceName]) -> eyre::Result<()> {
+    let ctx = settings.context(&reqwest::Client::new())?;
+    let services = ServiceName::selected(services);
+
+    if let Some(spotify_settings) = settings.spotify
+        && services.contains(&ServiceName::Spotify)
+    {
+        explain::explain::<Spotify>(&ctx, spotify_settings, track).await?;
+    }
+    if let Some(tidal_settings) = settings.tidal
+        && services.contains(&ServiceName::Tidal)
+    {
+        explain::explain::<Tidal>(&ctx, tidal_settings, track).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::ServiceName;
+
+    #[test]
+    fn test_service_order() {
+        // Spotify finds the ISRCs that Tidal searches by, so it always goes first.
+        let order = vec![ServiceName::Spotify, ServiceName::Tidal];
+        assert_eq!(order, ServiceName::selected(&[]));
+        assert_eq!(
+            order,
+            ServiceName::selected(&[ServiceName::Tidal, ServiceName::Spotify])
+        );
+        assert_eq!(
+            vec![ServiceName::Tidal],
+            ServiceName::selected(&[ServiceName::Tidal])
+        );
+    }
+}
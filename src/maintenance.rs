@@ -0,0 +1,140 @@
+//! Commands for inspecting and maintaining caches, so nobody has to hand-edit them.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    cache::{Cache, Status, Summary, store::Store},
+    track::Track,
+};
+
+/// Print every cached entry. With a query, only entries whose track or search result
+/// contains it (ignoring case) are printed.
+pub fn list(store: &dyn Store, services: &[&str], query: Option<&str>) -> eyre::Result<()> {
+    let query = query.map(str::to_lowercase);
+    for service in services {
+        let mut summaries = store.load(service)?.summaries();
+        summaries.retain(|summary| query.as_deref().is_none_or(|q| matches(summary, q)));
+        summaries.sort_by_key(|summary| summary.track.to_string());
+
+        for summary in summaries {
+            print_summary(service, &summary);
+        }
+    }
+    Ok(())
+}
+
+fn matches(summary: &Summary, query: &str) -> bool {
+    let track = &summary.track;
+    let mut haystacks = vec![&track.artist, &track.title];
+    if let Some(record) = &summary.record {
+        haystacks.push(&record.title);
+        haystacks.extend(&record.artists);
+    }
+    haystacks
+        .into_iter()
+        .any(|haystack| haystack.to_lowercase().contains(query))
+}
+
+fn print_summary(service: &str, summary: &Summary) {
+    let Summary {
+        track,
+        status,
+        record,
+        searched_at,
+        first_seen,
+        last_seen,
+    } = summary;
+    println!("{service:8} {status:10} {track}");
+    if let Some(record) = record {
+        println!("         candidate: {record}");
+    }
+    println!(
+        "         searched {}, first seen {}, last seen {}",
+        timestamp(*searched_at),
+        timestamp(*first_seen),
+        timestamp(*last_seen)
+    );
+}
+
+fn timestamp(secs: u64) -> String {
+    if secs == 0 {
+        return "unknown".into();
+    }
+    let time = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+/// Print how many tracks were found, not found, and rejected for each service.
+pub fn stats(store: &dyn Store, services: &[&str]) -> eyre::Result<()> {
+    println!(
+        "{:8} {:>6} {:>6} {:>9} {:>8}",
+        "service", "total", "found", "not found", "rejected"
+    );
+    for service in services {
+        let summaries = store.load(service)?.summaries();
+        let mut counts = HashMap::<Status, usize>::new();
+        for summary in &summaries {
+            *counts.entry(summary.status).or_default() += 1;
+        }
+        let count = |status| counts.get(&status).copied().unwrap_or_default();
+        println!(
+            "{service:8} {:>6} {:>6} {:>9} {:>8}",
+            summaries.len(),
+            count(Status::Found),
+            count(Status::NotFound),
+            count(Status::Rejected),
+        );
+    }
+    Ok(())
+}
+
+/// Forget a track, so it's searched for again on the next run.
+pub fn evict(store: &dyn Store, services: &[&str], track: &Track) -> eyre::Result<()> {
+    for service in services {
+        let cache = store.load(service)?;
+        if cache.evict(track) {
+            store.save(service, &cache)?;
+            println!("{service}: evicted {track}");
+        } else {
+            println!("{service}: {track} isn't cached");
+        }
+    }
+    Ok(())
+}
+
+/// Forget every track that wasn't found or was rejected.
+pub fn evict_negatives(store: &dyn Store, services: &[&str]) -> eyre::Result<()> {
+    for service in services {
+        let cache = store.load(service)?;
+        let evicted = cache.evict_negatives();
+        store.save(service, &cache)?;
+        println!("{service}: evicted {evicted} entries");
+    }
+    Ok(())
+}
+
+/// Write a service's cache to a JSON file, in the same format the JSON store uses.
+pub fn export(store: &dyn Store, service: &str, path: &Path) -> eyre::Result<()> {
+    store.load(service)?.save(path)?;
+    println!("{service}: exported to {}", path.display());
+    Ok(())
+}
+
+/// Read a JSON cache file of any known version into a service's cache. Entries in the
+/// file replace the ones we have; anything else is kept.
+pub fn import(store: &dyn Store, service: &str, path: &Path) -> eyre::Result<()> {
+    let imported = Cache::load(path, service)?;
+    let count = imported.summaries().len();
+    let cache = store.load(service)?;
+    cache.merge(imported);
+    store.save(service, &cache)?;
+    println!(
+        "{service}: imported {count} entries from {}",
+        path.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use eyre::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{cache, track::Track};
+
+/// A user-maintained correction for a single track.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    #[serde(flatten)]
+    pub track: Track,
+    /// Never add this track to any playlist, no matter what the search finds.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub never: bool,
+    /// Pinned ids, keyed by service name (e.g. `spotify`, `tidal`).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ids: BTreeMap<String, String>,
+}
+
+/// What to do with a track for a specific service, instead of searching for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Override {
+    Never,
+    Pin(String),
+}
+
+/// The contents of the overrides file. This is only ever written by hand, so we
+/// are lenient about what we accept: a missing file is just empty.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Overrides {
+    entries: Vec<Entry>,
+}
+
+impl Overrides {
+    pub fn load(path: &Path) -> eyre::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let overrides = serde_json::from_str(&std::fs::read_to_string(path)?)
+            .wrap_err_with(|| format!("failed to parse overrides file {}", path.display()))?;
+        Ok(overrides)
+    }
+
+    pub fn save(&self, path: &Path) -> eyre::Result<()> {
+        let ser = serde_json::to_string_pretty(self)?;
+        cache::write_atomic(path, ser.as_bytes())
+    }
+
+    /// Pin the given id for a track on a service, keeping any other overrides
+    /// for that track.
+    pub fn pin(&mut self, track: &Track, service: &str, id: String) {
+        let entry = match self.entries.iter_mut().find(|entry| &entry.track == track) {
+            Some(entry) => entry,
+            None => {
+                self.entries.push(Entry {
+                    track: track.clone(),
+                    never: false,
+                    ids: BTreeMap::new(),
+                });
+                self.entries.last_mut().unwrap()
+            }
+        };
+        entry.ids.insert(service.to_string(), id);
+    }
+
+    /// The overrides that apply to the service with the given name.
+    pub fn for_service(&self, service: &str) -> HashMap<Track, Override> {
+        self.entries
+            .iter()
+            .filter_map(|entry| {
+                let action = if entry.never {
+                    Override::Never
+                } else {
+                    Override::Pin(entry.ids.get(service)?.clone())
+                };
+                Some((entry.track.clone(), action))
+            })
+            .collect()
+    }
+}
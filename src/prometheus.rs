@@ -0,0 +1,162 @@
+//! Counters and histograms for Prometheus to scrape from the daemon.
+//!
+//! They're recorded with the `metrics` macros wherever things happen, and go nowhere
+//! unless [`install`] has been called.
+
+use std::time::Duration;
+
+use metrics::{counter, histogram};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use reqwest::Response;
+use tokio::net::TcpListener;
+use tracing::debug;
+
+use crate::http::{read_target, respond};
+
+/// Start keeping metrics, for [`serve`] to hand out.
+pub fn install() -> eyre::Result<PrometheusHandle> {
+    Ok(PrometheusBuilder::new()
+        .set_buckets(&[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0])?
+        .install_recorder()?)
+}
+
+/// Answer requests for `/metrics`, forever.
+pub async fn serve(listener: TcpListener, handle: PrometheusHandle) -> eyre::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            let (stream, target) = match read_target(stream).await {
+                Ok(request) => request,
+                Err(error) => {
+                    debug!(%error, "failed to read metrics request");
+                    return;
+                }
+            };
+            let path = target.unwrap_or_default();
+            let result = match path.split('?').next() {
+                Some("/metrics") => respond(stream, "200 OK", &handle.render()).await,
+                _ => respond(stream, "404 Not Found", "").await,
+            };
+            if let Err(error) = result {
+                debug!(%error, "failed to serve metrics");
+            }
+        });
+    }
+}
+
+/// Count a request's response by host and status, and time it by endpoint.
+pub(crate) fn request(result: &Result<Response, reqwest::Error>, elapsed: Duration) {
+    let (url, status) = match result {
+        Ok(response) => (Some(response.url()), response.status().as_str().to_owned()),
+        Err(error) => (error.url(), "error".to_owned()),
+    };
+    let Some(url) = url else {
+        return;
+    };
+    let host = url.host_str().unwrap_or_default().to_owned();
+    counter!("playlister_http_responses_total", "host" => host, "status" => status).increment(1);
+    histogram!("playlister_http_request_duration_seconds", "endpoint" => endpoint(url))
+        .record(elapsed.as_secs_f64());
+}
+
+/// Collections whose next path segment is an id, or a search query.
+const COLLECTIONS: [&str; 6] = [
+    "playlists",
+    "tracks",
+    "artists",
+    "searchResults",
+    "r",
+    "users",
+];
+
+/// The host and path of a URL, with ids and queries taken out so that every request to
+/// an endpoint has the same one; e.g. `api.spotify.com/v1/playlists/{id}/tracks`.
+pub fn endpoint(url: &reqwest::Url) -> String {
+    let mut endpoint = url.host_str().unwrap_or_default().to_owned();
+    let mut after_collection = false;
+    for segment in url.path_segments().into_iter().flatten() {
+        endpoint.push('/');
+        if after_collection {
+            endpoint.push_str("{id}");
+            after_collection = false;
+        } else {
+            endpoint.push_str(segment);
+            after_collection = COLLECTIONS.contains(&segment);
+        }
+    }
+    endpoint
+}
+
+#[cfg(test)]
+mod test {
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{endpoint, serve};
+
+    #[test]
+    fn test_endpoint() {
+        let endpoint = |url: &str| endpoint(&url.parse().unwrap());
+        assert_eq!(
+            "api.spotify.com/v1/playlists/{id}/tracks",
+            endpoint(
+                "https://api.spotify.com/v1/playlists/0QLH8AqDfjGmcWK1vnf2sI/tracks?limit=100"
+            )
+        );
+        assert_eq!(
+            "openapi.tidal.com/v2/searchResults/{id}",
+            endpoint("https://openapi.tidal.com/v2/searchResults/Big%20Thief%20What%3F")
+        );
+        assert_eq!(
+            "openapi.tidal.com/v2/tracks/{id}/relationships/artists",
+            endpoint("https://openapi.tidal.com/v2/tracks/123/relationships/artists")
+        );
+        assert_eq!(
+            "openapi.tidal.com/v2/tracks",
+            endpoint("https://openapi.tidal.com/v2/tracks?filter[isrc]=X")
+        );
+        assert_eq!(
+            "oauth.reddit.com/r/{id}/hot",
+            endpoint("https://oauth.reddit.com/r/listentothis/hot?limit=100")
+        );
+    }
+
+    async fn get(port: u16, target: &str) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let request = format!("GET {target} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            metrics::counter!("playlister_test_total").increment(3);
+        });
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(serve(listener, handle));
+
+        let response = get(port, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("playlister_test_total 3"));
+        assert!(get(port, "/").await.starts_with("HTTP/1.1 404"));
+
+        // A request line that never ends is cut off, rather than read forever.
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream.write_all(&[b'a'; 10_000]).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 404"));
+        server.abort();
+    }
+}
@@ -0,0 +1,166 @@
+//! Keeping credentials out of errors and logs.
+//!
+//! Every [`Secret`](crate::Secret) that's made is remembered here, so that it can be
+//! masked wherever it turns up, along with anything in a field that's named like a
+//! credential. Tokens that services hand out as we go only stay here until enough newer
+//! ones have come along, so that a daemon doesn't check every log line against every
+//! token it's ever had.
+
+use std::{
+    collections::{BTreeSet, VecDeque},
+    io::{self, Write},
+    sync::{LazyLock, RwLock},
+};
+
+use regex::Regex;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// What credentials are replaced with.
+const MASK: &str = "<SECRET>";
+
+/// Anything shorter is more likely to be a word than a credential, and masking every
+/// place a word appears would make the logs useless.
+const MIN_LEN: usize = 8;
+
+/// How many of the tokens handed out as we go are masked. They're replaced every hour or
+/// so, and only the last few of them are still any good.
+const RECENT_LEN: usize = 32;
+
+static SECRETS: RwLock<BTreeSet<String>> = RwLock::new(BTreeSet::new());
+
+/// Short-lived tokens, oldest first.
+static RECENT: RwLock<VecDeque<String>> = RwLock::new(VecDeque::new());
+
+/// JSON fields and query or form parameters that hold credentials, however they're
+/// spelled.
+static FIELDS: LazyLock<Regex> = LazyLock::new(|| {
+    let names = r"access_?token|refresh_?token|id_?token|client_?id|client_?secret|device_?code|code_?verifier";
+    Regex::new(&format!(
+        r#"(?i)("(?:{names})"\s*:\s*)"[^"]*"|\b((?:{names})=)[^&\s"]+"#
+    ))
+    .unwrap()
+});
+
+/// Mask this value wherever it turns up from now on.
+pub fn register(secret: &str) {
+    if secret.len() < MIN_LEN {
+        return;
+    }
+    let mut secrets = SECRETS.write().unwrap_or_else(|e| e.into_inner());
+    secrets.insert(secret.to_owned());
+}
+
+/// Mask this value wherever it turns up, until [`RECENT_LEN`] newer ones have been
+/// registered; for tokens that are only good for a while, like access tokens.
+pub fn register_recent(secret: &str) {
+    if secret.len() < MIN_LEN {
+        return;
+    }
+    let mut recent = RECENT.write().unwrap_or_else(|e| e.into_inner());
+    if recent.iter().any(|s| s == secret) {
+        return;
+    }
+    if recent.len() == RECENT_LEN {
+        recent.pop_front();
+    }
+    recent.push_back(secret.to_owned());
+}
+
+/// The text, with every credential we know of, and every field that looks like it has
+/// one, masked.
+pub fn redact(text: &str) -> String {
+    let mut text = FIELDS
+        .replace_all(text, |captures: &regex::Captures| match captures.get(1) {
+            Some(field) => format!("{}\"{MASK}\"", field.as_str()),
+            None => format!("{}{MASK}", &captures[2]),
+        })
+        .into_owned();
+
+    let secrets = SECRETS.read().unwrap_or_else(|e| e.into_inner());
+    let recent = RECENT.read().unwrap_or_else(|e| e.into_inner());
+    // Longest first, in case one contains another.
+    let mut secrets = secrets.iter().chain(recent.iter()).collect::<Vec<_>>();
+    secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+    for secret in secrets {
+        if text.contains(secret.as_str()) {
+            text = text.replace(secret.as_str(), MASK);
+        }
+    }
+    text
+}
+
+/// Writes log lines to stdout, redacted.
+pub struct Writer;
+
+impl<'a> MakeWriter<'a> for Writer {
+    type Writer = Line;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Line(Vec::new())
+    }
+}
+
+/// A log line, which is redacted as a whole once it's done so that a credential split
+/// across writes is still caught.
+pub struct Line(Vec<u8>);
+
+impl Write for Line {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Line {
+    fn drop(&mut self) {
+        let line = redact(&String::from_utf8_lossy(&self.0));
+        let _ = io::stdout().lock().write_all(line.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RECENT, RECENT_LEN, redact, register, register_recent};
+
+    #[test]
+    fn test_redact() {
+        register("hunter2hunter2");
+        register("short");
+        assert_eq!(
+            "the password is <SECRET>, and short",
+            redact("the password is hunter2hunter2, and short")
+        );
+
+        assert_eq!(
+            r#"{"access_token": "<SECRET>", "expires_in": 3600, "refreshToken":"<SECRET>"}"#,
+            redact(r#"{"access_token": "abc", "expires_in": 3600, "refreshToken":"def"}"#)
+        );
+        assert_eq!(
+            "https://example.com/?client_id=<SECRET>&scope=all",
+            redact("https://example.com/?client_id=abc&scope=all")
+        );
+        assert_eq!(
+            r#"{"error": "invalid_grant"}"#,
+            redact(r#"{"error": "invalid_grant"}"#)
+        );
+    }
+
+    #[test]
+    fn test_recent() {
+        register_recent("access-token-0");
+        assert_eq!("Bearer <SECRET>", redact("Bearer access-token-0"));
+
+        // Only the latest are kept, however many there have been.
+        for n in 1..=RECENT_LEN * 2 {
+            register_recent(&format!("access-token-{n}"));
+        }
+        assert!(RECENT.read().unwrap().len() <= RECENT_LEN);
+        assert_eq!("Bearer access-token-0", redact("Bearer access-token-0"));
+        let latest = format!("Bearer access-token-{}", RECENT_LEN * 2);
+        assert_eq!("Bearer <SECRET>", redact(&latest));
+    }
+}
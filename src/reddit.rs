@@ -1,15 +1,84 @@
-use crate::{AuthResponse, JsonRequest, Secret, track::Track};
+use crate::{
+    AuthResponse, JsonRequest, Secret,
+    tokens::{Authenticate, Token},
+    track::Track,
+};
+use eyre::{OptionExt, WrapErr};
+use metrics::counter;
+use regex::Regex;
 use serde::Deserialize;
 use std::env;
-use tracing::warn;
+use tracing::{Span, debug, field, warn};
 
 struct Post {
     title: String,
+    score: i64,
 }
 
-impl Post {
-    fn new(title: String) -> Post {
-        Post { title }
+/// Captures the artist and title of a post on r/listentothis.
+const LISTENTOTHIS_REGEX: &str = r"(.*?)\s+[-–—\s]+\s+(.*?)\s*[\(\[]";
+
+/// Where to take tracks from, and which posts to skip.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Source {
+    /// Without the `r/`.
+    pub subreddit: String,
+    pub sort: Sort,
+    /// Captures the artist and title of a post, in that order. Defaults to one that
+    /// suits r/listentothis.
+    regex: Option<String>,
+    /// Skip posts with a lower score than this.
+    min_score: Option<i64>,
+    /// Skip posts whose titles match any of these regexes.
+    exclude: Vec<String>,
+}
+
+impl Default for Source {
+    fn default() -> Self {
+        Self {
+            subreddit: "listentothis".into(),
+            sort: Sort::default(),
+            regex: None,
+            min_score: None,
+            exclude: Vec::new(),
+        }
+    }
+}
+
+impl Source {
+    pub fn regex(&self) -> eyre::Result<Regex> {
+        let regex = self.regex.as_deref().unwrap_or(LISTENTOTHIS_REGEX);
+        Regex::new(regex).wrap_err("invalid title regex")
+    }
+
+    fn exclude(&self) -> eyre::Result<Vec<Regex>> {
+        self.exclude
+            .iter()
+            .map(|regex| Regex::new(regex).wrap_err("invalid exclude regex"))
+            .collect()
+    }
+}
+
+/// Which of a subreddit's listings to read.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    #[default]
+    Hot,
+    New,
+    Rising,
+    Top,
+}
+
+impl Sort {
+    fn as_str(self) -> &'static str {
+        match self {
+            Sort::Hot => "hot",
+            Sort::New => "new",
+            Sort::Rising => "rising",
+            Sort::Top => "top",
+        }
     }
 }
 
@@ -20,37 +89,74 @@ pub struct Settings {
 }
 
 pub struct Reddit {
-    access_token: Secret<String>,
+    token: Token,
     client: reqwest::Client,
 }
 
 impl Reddit {
     pub async fn new(config: Settings, client: reqwest::Client) -> eyre::Result<Reddit> {
-        let access_token = get_access_token(&client, &config).await?;
+        let token = {
+            let client = client.clone();
+            Token::new("reddit", "app", None, None, move |_| {
+                get_access_token(
+                    client.clone(),
+                    config.client_id.clone(),
+                    config.client_secret.clone(),
+                )
+            })
+        };
+        // Make sure we can log in before going any further.
+        token.get().await?;
+
+        Ok(Reddit { token, client })
+    }
 
-        Ok(Reddit {
-            access_token,
-            client,
-        })
+    /// Log in with the settings; it's an error if there aren't any.
+    pub async fn login(
+        settings: Option<Settings>,
+        client: &reqwest::Client,
+    ) -> eyre::Result<Reddit> {
+        let settings =
+            settings.ok_or_eyre("REDDIT__CLIENT_ID and REDDIT__CLIENT_SECRET must be set")?;
+        Reddit::new(settings, client.clone()).await
     }
 
-    pub async fn tracks(&self, subreddit: &str, regex: regex::Regex) -> eyre::Result<Vec<Track>> {
+    /// The tracks currently posted to the source's subreddit.
+    #[tracing::instrument(name = "reddit", skip_all, fields(subreddit = source.subreddit, count = field::Empty))]
+    pub async fn tracks(&self, source: &Source) -> eyre::Result<Vec<Track>> {
+        let regex = source.regex()?;
+        let exclude = source.exclude()?;
+        let subreddit = source.subreddit.clone();
         let tracks: Vec<_> = self
-            .posts(subreddit)
+            .posts(source)
             .await?
-            .map(|post| post.title)
-            .filter_map(move |title| match regex.captures(&title) {
-                Some(cap) => Some(Track::new(cap[1].to_string(), cap[2].to_string())),
-                None => {
-                    warn!("Failed to match: {}", title);
-                    None
+            .inspect(|_| {
+                counter!("playlister_posts_scraped_total", "subreddit" => subreddit.clone())
+                    .increment(1)
+            })
+            .filter(|post| {
+                let keep = source.min_score.is_none_or(|min| post.score >= min)
+                    && !exclude.iter().any(|regex| regex.is_match(&post.title));
+                if !keep {
+                    debug!(title = post.title, score = post.score, "skipping post");
+                }
+                keep
+            })
+            .filter_map(|post| {
+                let track = parse_title(&regex, &post.title);
+                if track.is_none() {
+                    warn!("Failed to match: {}", post.title);
+                    counter!("playlister_parse_failures_total", "subreddit" => subreddit.clone())
+                        .increment(1);
                 }
+                track
             })
             .collect();
+        Span::current().record("count", tracks.len());
         Ok(tracks)
     }
 
-    async fn posts(&self, subreddit: &str) -> eyre::Result<impl Iterator<Item = Post> + use<>> {
+    async fn posts(&self, source: &Source) -> eyre::Result<impl Iterator<Item = Post> + use<>> {
         #[derive(Deserialize, Debug)]
         struct Response {
             data: ResponseData,
@@ -69,49 +175,61 @@ impl Reddit {
         #[derive(Deserialize, Debug)]
         struct ChildData {
             title: String,
+            #[serde(default)]
+            score: i64,
         }
 
         let posts = self
             .client
-            .get(url(subreddit))
-            .bearer_auth(self.access_token.expose_secret())
+            .get(url(source))
             .header(
                 reqwest::header::USER_AGENT,
                 format!("listothis-playlist-updater/{}", env!("CARGO_PKG_VERSION")),
             )
+            .with_token(&self.token)
             .send_it_json::<Response>()
             .await?
             .data
             .children
             .into_iter()
-            .map(|child| child.data.title)
-            .map(|title| htmlescape::decode_html(&title).unwrap_or(title))
-            .map(Post::new);
+            .map(|child| Post {
+                title: htmlescape::decode_html(&child.data.title).unwrap_or(child.data.title),
+                score: child.data.score,
+            });
         Ok(posts)
     }
 }
 
-fn url(subreddit: &str) -> String {
-    format!("https://oauth.reddit.com/{}?limit=100", subreddit)
+/// Pull the artist and title out of a post title, with a regex that captures them in
+/// that order.
+pub fn parse_title(regex: &Regex, title: &str) -> Option<Track> {
+    let cap = regex.captures(title)?;
+    Some(Track::new(cap[1].to_string(), cap[2].to_string()))
+}
+
+fn url(source: &Source) -> String {
+    format!(
+        "https://oauth.reddit.com/r/{}/{}?limit=100",
+        source.subreddit,
+        source.sort.as_str()
+    )
 }
 
 async fn get_access_token(
-    client: &reqwest::Client,
-    config: &Settings,
-) -> eyre::Result<Secret<String>> {
+    client: reqwest::Client,
+    client_id: String,
+    client_secret: Secret<String>,
+) -> eyre::Result<AuthResponse> {
     let response: AuthResponse = client
         .post("https://www.reddit.com/api/v1/access_token")
-        .basic_auth(
-            &config.client_id,
-            Some(config.client_secret.expose_secret()),
-        )
+        .basic_auth(&client_id, Some(client_secret.expose_secret()))
         .header(
             reqwest::header::USER_AGENT,
             format!("listothis-playlist-updater/{}", env!("CARGO_PKG_VERSION")),
         )
         .form(&[("grant_type", "client_credentials")])
-        .send_it_json()
+        .send_it_secret_json()
         .await?;
 
-    Ok(response.access_token)
+    Ok(response)
 }
@@ -0,0 +1,105 @@
+use std::{
+    io::{self, BufRead, Write},
+    path::Path,
+};
+
+use crate::{
+    cache::{Rejection, store::Store},
+    overrides::Overrides,
+};
+
+enum Decision {
+    Approve,
+    Confirm,
+    Skip,
+    Quit,
+}
+
+/// Walk through every unreviewed rejection in the caches of the given services.
+///
+/// Approving a rejection pins the candidate in the overrides file and marks it as
+/// accepted in the cache. Confirming it marks it as reviewed, so it won't be
+/// shown again. With `list_only`, rejections are printed and nothing is changed.
+pub fn review(
+    store: &dyn Store,
+    overrides_file: &Path,
+    services: &[&str],
+    list_only: bool,
+) -> eyre::Result<()> {
+    let mut overrides = Overrides::load(overrides_file)?;
+    let mut approved = 0;
+    let mut stdin = io::stdin().lock();
+
+    'services: for service in services {
+        let cache = store.load(service)?;
+        let mut rejections = cache.rejections();
+        rejections.sort_by_key(|rejection| rejection.track.to_string());
+
+        println!("{service}: {} rejected", rejections.len());
+        for rejection in rejections {
+            print_rejection(&rejection);
+            if list_only {
+                continue;
+            }
+
+            match prompt(&mut stdin)? {
+                Decision::Approve => {
+                    cache.approve(&rejection.track);
+                    overrides.pin(&rejection.track, service, rejection.record.id.clone());
+                    approved += 1;
+                }
+                Decision::Confirm => cache.confirm(&rejection.track),
+                Decision::Skip => (),
+                Decision::Quit => {
+                    store.save(service, &cache)?;
+                    break 'services;
+                }
+            }
+        }
+
+        if !list_only {
+            store.save(service, &cache)?;
+        }
+    }
+
+    if approved > 0 {
+        overrides.save(overrides_file)?;
+        println!("Pinned {approved} tracks in {}", overrides_file.display());
+    }
+
+    Ok(())
+}
+
+fn print_rejection(rejection: &Rejection) {
+    let Rejection {
+        track,
+        record,
+        scores,
+    } = rejection;
+    println!();
+    println!("  track:     {track}");
+    println!("  candidate: {record}");
+    println!(
+        "  scores:    title {:.2}, artist {:.2}",
+        scores.title, scores.artist
+    );
+}
+
+fn prompt(stdin: &mut impl BufRead) -> eyre::Result<Decision> {
+    loop {
+        print!("  [a]pprove, [c]onfirm rejection, [s]kip, [q]uit? ");
+        io::stdout().flush()?;
+
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 {
+            return Ok(Decision::Quit);
+        }
+        match line.trim() {
+            "a" => return Ok(Decision::Approve),
+            "c" => return Ok(Decision::Confirm),
+            "s" | "" => return Ok(Decision::Skip),
+            "q" => return Ok(Decision::Quit),
+            _ => continue,
+        }
+    }
+}
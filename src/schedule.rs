@@ -0,0 +1,87 @@
+//! When the daemon syncs a job.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Local, TimeDelta};
+use croner::Cron;
+use eyre::{WrapErr, bail};
+use tracing::warn;
+
+/// Every so often, or whenever a cron expression matches.
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    Every(Duration),
+    Cron(Box<Cron>),
+}
+
+impl Schedule {
+    /// From a job's settings; hourly if it has neither.
+    pub fn new(interval: Option<Duration>, cron: Option<&str>) -> eyre::Result<Self> {
+        match (interval, cron) {
+            (Some(_), Some(_)) => bail!("set either an interval or a cron expression, not both"),
+            (Some(interval), None) if interval.is_zero() => bail!("the interval can't be zero"),
+            (Some(interval), None) => Ok(Self::Every(interval)),
+            (None, Some(cron)) => {
+                let cron = cron
+                    .parse()
+                    .wrap_err_with(|| format!("invalid cron expression {cron:?}"))?;
+                Ok(Self::Cron(Box::new(cron)))
+            }
+            (None, None) => Ok(Self::Every(Duration::from_secs(60 * 60))),
+        }
+    }
+
+    /// The first time after the given one that a run is due.
+    fn after(&self, time: DateTime<Local>) -> eyre::Result<DateTime<Local>> {
+        match self {
+            Schedule::Every(interval) => Ok(time + TimeDelta::from_std(*interval)?),
+            Schedule::Cron(cron) => Ok(cron.find_next_occurrence(&time, false)?),
+        }
+    }
+
+    /// When the next run is due, given when the last one was. If that's already passed,
+    /// because the last run took too long, the runs in between are skipped.
+    pub fn next(
+        &self,
+        last: DateTime<Local>,
+        now: DateTime<Local>,
+    ) -> eyre::Result<DateTime<Local>> {
+        let next = self.after(last)?;
+        if next >= now {
+            return Ok(next);
+        }
+        warn!(due = %next, "the last run was still going when the next was due; skipping it");
+        self.after(now)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use chrono::{Local, TimeDelta, TimeZone};
+
+    use super::Schedule;
+
+    #[test]
+    fn test_schedule() {
+        let start = Local.with_ymd_and_hms(2026, 1, 1, 12, 30, 0).unwrap();
+        let minutes = |n| start + TimeDelta::minutes(n);
+
+        let hourly = Schedule::new(None, None).unwrap();
+        assert_eq!(minutes(60), hourly.next(start, minutes(10)).unwrap());
+        // The run took longer than the interval, so the one that was due is skipped.
+        assert_eq!(minutes(150), hourly.next(start, minutes(90)).unwrap());
+
+        let every = Schedule::new(Some(Duration::from_secs(15 * 60)), None).unwrap();
+        assert_eq!(minutes(15), every.next(start, minutes(1)).unwrap());
+
+        let cron = Schedule::new(None, Some("0 * * * *")).unwrap();
+        assert_eq!(minutes(30), cron.next(start, minutes(1)).unwrap());
+        assert_eq!(minutes(150), cron.next(start, minutes(100)).unwrap());
+
+        assert!(Schedule::new(Some(Duration::from_secs(60)), Some("0 * * * *")).is_err());
+        assert!(Schedule::new(Some(Duration::ZERO), None).is_err());
+        assert!(Schedule::new(None, Some("every hour")).is_err());
+    }
+}
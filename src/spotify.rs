@@ -1,19 +1,39 @@
-use crate::{AuthResponse, Data, JsonRequest, Record, Secret, Service, track::Track};
+use crate::{
+    AuthResponse, Data, JsonRequest, Query, Record, Secret, Service,
+    tokens::{Authenticate, Credentials, Token},
+    track::Track,
+};
+use eyre::OptionExt;
 use serde::Deserialize;
 use serde_json::json;
+use tracing::debug;
 
 #[derive(Deserialize, Debug)]
 pub struct Settings {
     client_id: String,
     client_secret: Secret<String>,
-    refresh_token: Secret<String>,
+    /// Not needed in the environment once `playlister auth spotify` has stored one.
+    refresh_token: Option<Secret<String>>,
     playlist_id: String,
+    /// An ISO 3166-1 alpha-2 country code, e.g. `DE`. If set, only tracks that can be
+    /// played there are added.
+    market: Option<String>,
+}
+
+impl Settings {
+    /// Fill in whatever we have stored from logging in.
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        if let Some(refresh_token) = credentials.refresh_token {
+            self.refresh_token = Some(refresh_token);
+        }
+        self
+    }
 }
 
 pub struct Spotify {
     data: Data<Self>,
-    app_access_token: Secret<String>,
-    user_access_token: Secret<String>,
+    app_token: Token,
+    user_token: Token,
 }
 
 impl Service for Spotify {
@@ -21,30 +41,43 @@ impl Service for Spotify {
     type Settings = Settings;
 
     async fn new(data: Data<Self>) -> eyre::Result<Self> {
-        let app_access_token = data.get_app_access_token().await?;
-        let user_access_token = data.get_user_access_token().await?;
+        let app_token = data.app_token();
+        let user_token = data.user_token()?;
+        // Make sure we can log in before going any further.
+        app_token.get().await?;
+        user_token.get().await?;
 
         Ok(Self {
             data,
-            app_access_token,
-            user_access_token,
+            app_token,
+            user_token,
         })
     }
 
-    async fn run(&self) -> eyre::Result<()> {
-        self.update_playlist().await
+    fn data(&self) -> &Data<Self> {
+        &self.data
     }
-}
 
-impl Spotify {
-    async fn update_playlist(&self) -> eyre::Result<()> {
-        let uris = self
-            .data
-            .search_all(|t| self.search(t))
-            .await
-            .into_iter()
-            .map(|r| r.id)
-            .collect::<Vec<_>>();
+    fn queries(&self, track: &Track) -> Vec<Query> {
+        let isrc = self.data.isrcs.get(track).map(Query::Isrc);
+        isrc.into_iter()
+            .chain([Query::Text(track.as_spotify_query())])
+            .collect()
+    }
+
+    async fn query(&self, query: &Query) -> eyre::Result<Option<Record>> {
+        match query {
+            Query::Isrc(isrc) => self.search_query(&format!("isrc:{isrc}")).await,
+            Query::Text(text) => self.search_query(text).await,
+        }
+    }
+
+    fn playlist_id(&self) -> &str {
+        &self.data.settings.playlist_id
+    }
+
+    async fn update_playlist(&self, records: Vec<Record>) -> eyre::Result<()> {
+        let uris = records.into_iter().map(|r| r.id).collect::<Vec<_>>();
 
         let body = json!({ "uris": uris });
 
@@ -52,17 +85,19 @@ impl Spotify {
             .client
             .put(format!(
                 "https://api.spotify.com/v1/playlists/{}/tracks",
-                self.data.settings.playlist_id
+                self.playlist_id()
             ))
-            .bearer_auth(self.user_access_token.expose_secret())
             .json(&body)
+            .with_token(&self.user_token)
             .send_it()
             .await?;
 
         Ok(())
     }
+}
 
-    async fn search(&self, track: &Track) -> eyre::Result<Option<Record>> {
+impl Spotify {
+    async fn search_query(&self, query: &str) -> eyre::Result<Option<Record>> {
         #[derive(Deserialize, Debug)]
         struct Response {
             tracks: Items,
@@ -78,6 +113,10 @@ impl Spotify {
             uri: String,
             name: String,
             artists: Vec<Artist>,
+            #[serde(default)]
+            external_ids: ExternalIds,
+            /// Only there if we asked for a market.
+            is_playable: Option<bool>,
         }
 
         #[derive(Deserialize, Debug)]
@@ -85,23 +124,39 @@ impl Spotify {
             name: String,
         }
 
+        #[derive(Deserialize, Debug, Default)]
+        struct ExternalIds {
+            isrc: Option<String>,
+        }
+
+        let mut params = vec![("type", "track"), ("q", query), ("limit", "5")];
+        if let Some(market) = &self.data.settings.market {
+            params.push(("market", market));
+        }
         let response: Response = self
             .data
             .client
             .get("https://api.spotify.com/v1/search")
-            .bearer_auth(self.app_access_token.expose_secret())
-            .query(&[
-                ("type", "track"),
-                ("q", &track.as_spotify_query()),
-                ("limit", "1"),
-            ])
+            .query(&params)
+            .with_token(&self.app_token)
             .send_it_json()
             .await?;
 
-        let record = response.tracks.items.into_iter().next().map(|item| Record {
+        let playable = response.tracks.items.into_iter().find(|item| {
+            let playable = item.is_playable != Some(false);
+            if !playable {
+                debug!(
+                    uri = item.uri,
+                    "skipping a track that isn't playable in our market"
+                );
+            }
+            playable
+        });
+        let record = playable.map(|item| Record {
             id: item.uri,
             title: item.name,
             artists: item.artists.into_iter().map(|artist| artist.name).collect(),
+            isrc: item.external_ids.isrc,
         });
 
         Ok(record)
@@ -109,31 +164,59 @@ impl Spotify {
 }
 
 impl Data<Spotify> {
-    async fn get_app_access_token(&self) -> eyre::Result<Secret<String>> {
-        self.get_access_token(&[("grant_type", "client_credentials")])
-            .await
+    fn app_token(&self) -> Token {
+        self.token("app", None)
     }
 
-    async fn get_user_access_token(&self) -> eyre::Result<Secret<String>> {
-        self.get_access_token(&[
-            ("grant_type", "refresh_token"),
-            ("refresh_token", self.settings.refresh_token.expose_secret()),
-        ])
-        .await
+    fn user_token(&self) -> eyre::Result<Token> {
+        let refresh_token = self.settings.refresh_token.clone().ok_or_eyre(
+            "no Spotify refresh token; run `playlister auth spotify` or set SPOTIFY__REFRESH_TOKEN",
+        )?;
+        Ok(self.token("user", Some(refresh_token)))
     }
 
-    async fn get_access_token(&self, body: &[(&str, &str)]) -> eyre::Result<Secret<String>> {
-        let response: AuthResponse = self
-            .client
-            .post("https://accounts.spotify.com/api/token")
-            .basic_auth(
-                &self.settings.client_id,
-                Some(self.settings.client_secret.expose_secret()),
-            )
-            .form(&body)
-            .send_it_json()
-            .await?;
-
-        Ok(response.access_token)
+    /// Without a refresh token, the token is for the app rather than a user.
+    fn token(&self, grant: &'static str, refresh_token: Option<Secret<String>>) -> Token {
+        let client = self.client.clone();
+        let client_id = self.settings.client_id.clone();
+        let client_secret = self.settings.client_secret.clone();
+        Token::new(
+            Spotify::NAME,
+            grant,
+            self.tokens.clone(),
+            refresh_token,
+            move |refresh_token| {
+                get_access_token(
+                    client.clone(),
+                    client_id.clone(),
+                    client_secret.clone(),
+                    refresh_token,
+                )
+            },
+        )
     }
 }
+
+async fn get_access_token(
+    client: reqwest::Client,
+    client_id: String,
+    client_secret: Secret<String>,
+    refresh_token: Option<Secret<String>>,
+) -> eyre::Result<AuthResponse> {
+    let body = match &refresh_token {
+        Some(refresh_token) => vec![
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token.expose_secret().as_str()),
+        ],
+        None => vec![("grant_type", "client_credentials")],
+    };
+    let response: AuthResponse = client
+        .post("https://accounts.spotify.com/api/token")
+        .basic_auth(&client_id, Some(client_secret.expose_secret()))
+        .form(&body)
+        .send_it_secret_json()
+        .await?;
+    debug!(expires_in = response.expires_in, "got access token");
+
+    Ok(response)
+}
@@ -0,0 +1,191 @@
+//! Sending traces to an OpenTelemetry collector.
+//!
+//! Spans are exported over OTLP/HTTP in batches, from a thread of their own. Every
+//! request to Reddit, Spotify or Tidal is a span, under the sync it's part of.
+//! Credentials are masked in everything that's exported, as they are in logs.
+
+use std::{borrow::Cow, time::Duration};
+
+use opentelemetry::{
+    Array, KeyValue, StringValue, Value,
+    trace::{Status, TracerProvider},
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    Resource,
+    error::OTelSdkResult,
+    trace::{SdkTracerProvider, SpanData, SpanExporter},
+};
+use tracing::Subscriber;
+use tracing_subscriber::{Layer, registry::LookupSpan};
+
+use crate::redact::redact;
+
+/// Exports spans until it's dropped, and then sends whatever's left.
+pub struct Telemetry(SdkTracerProvider);
+
+impl Telemetry {
+    /// Export to the collector at `endpoint`, e.g. `http://localhost:4318`.
+    ///
+    /// This has to be called outside of the async runtime, because the exporter's
+    /// HTTP client blocks.
+    pub fn new(endpoint: &str) -> eyre::Result<Self> {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(traces_url(endpoint))
+            .build()?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(Redacted(exporter))
+            .with_resource(Resource::builder().with_service_name("playlister").build())
+            .build();
+        Ok(Self(provider))
+    }
+
+    /// A layer that hands spans to the exporter.
+    pub fn layer<S>(&self) -> impl Layer<S> + use<S>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer().with_tracer(self.0.tracer("playlister"))
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Err(error) = self.0.shutdown() {
+            eprintln!("failed to export the last traces: {error}");
+        }
+    }
+}
+
+/// An exporter that masks credentials in spans' names, fields, events and statuses
+/// before handing them on.
+#[derive(Debug)]
+struct Redacted<E>(E);
+
+impl<E: SpanExporter> SpanExporter for Redacted<E> {
+    fn export(&self, mut batch: Vec<SpanData>) -> impl Future<Output = OTelSdkResult> + Send {
+        for span in &mut batch {
+            redact_str(&mut span.name);
+            redact_attributes(&mut span.attributes);
+            for event in &mut span.events.events {
+                redact_str(&mut event.name);
+                redact_attributes(&mut event.attributes);
+            }
+            if let Status::Error { description } = &mut span.status {
+                redact_str(description);
+            }
+        }
+        self.0.export(batch)
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.0.shutdown_with_timeout(timeout)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.0.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.0.set_resource(resource)
+    }
+}
+
+fn redact_str(text: &mut Cow<'static, str>) {
+    *text = Cow::Owned(redact(text));
+}
+
+fn redact_attributes(attributes: &mut [KeyValue]) {
+    let redact_value = |value: &StringValue| StringValue::from(redact(value.as_str()));
+    for KeyValue { value, .. } in attributes {
+        match value {
+            Value::String(string) => *string = redact_value(string),
+            Value::Array(Array::String(strings)) => {
+                strings.iter_mut().for_each(|s| *s = redact_value(s))
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Collectors take traces at `/v1/traces`, which we'll add unless it's been given.
+fn traces_url(endpoint: &str) -> String {
+    let endpoint = endpoint.trim_end_matches('/');
+    if endpoint.ends_with("/v1/traces") {
+        endpoint.to_owned()
+    } else {
+        format!("{endpoint}/v1/traces")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use opentelemetry_sdk::{
+        error::OTelSdkResult,
+        trace::{SdkTracerProvider, SpanData, SpanExporter},
+    };
+    use tracing::{error, info_span};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use crate::redact::register;
+
+    use super::{Redacted, Telemetry, traces_url};
+
+    /// Keeps what it's given, rather than sending it anywhere.
+    #[derive(Clone, Debug, Default)]
+    struct Collector(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for Collector {
+        async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+            self.0.lock().unwrap().extend(batch);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_redacted() {
+        register("otlp-secret-hunter2");
+        let collector = Collector::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(Redacted(collector.clone()))
+            .build();
+        let telemetry = Telemetry(provider);
+        let subscriber = tracing_subscriber::registry().with(telemetry.layer());
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = info_span!(
+                "request",
+                url.full = "https://example.com/?refresh_token=abcdefghij",
+                token = "otlp-secret-hunter2",
+            );
+            let _enter = span.enter();
+            error!(error = "rejected otlp-secret-hunter2", "request failed");
+        });
+        drop(telemetry);
+
+        let spans = format!("{:?}", collector.0.lock().unwrap());
+        assert!(spans.contains("request failed"));
+        assert!(spans.contains("refresh_token=<SECRET>"));
+        assert!(!spans.contains("abcdefghij"));
+        assert!(!spans.contains("otlp-secret-hunter2"));
+    }
+
+    #[test]
+    fn test_traces_url() {
+        assert_eq!(
+            "http://localhost:4318/v1/traces",
+            traces_url("http://localhost:4318")
+        );
+        assert_eq!(
+            "http://localhost:4318/v1/traces",
+            traces_url("http://localhost:4318/")
+        );
+        assert_eq!(
+            "http://collector/otlp/v1/traces",
+            traces_url("http://collector/otlp/v1/traces")
+        );
+    }
+}
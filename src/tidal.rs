@@ -1,21 +1,59 @@
-use crate::{AuthResponse, Data, JsonRequest, Record, Secret, Service, track::Track};
-use futures::{StreamExt, stream::FuturesOrdered};
+use crate::{
+    AuthResponse, Data, JsonRequest, Query, Record, Secret, Service,
+    tokens::{Authenticate, Credentials, Token},
+    track::Track,
+};
+use dashmap::DashMap;
+use eyre::{OptionExt, bail, eyre};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use tracing::debug;
 
 #[derive(Deserialize, Debug)]
 pub struct Settings {
-    client_id: String,
-    client_secret: Secret<String>,
-    refresh_token: Secret<String>,
+    // These three aren't needed in the environment once `playlister auth tidal` has
+    // stored them.
+    client_id: Option<String>,
+    client_secret: Option<Secret<String>>,
+    refresh_token: Option<Secret<String>>,
     playlist_id: String,
+    /// An ISO 3166-1 alpha-2 country code, e.g. `DE`. Only tracks that can be played
+    /// there are added.
+    #[serde(default = "us")]
+    country_code: String,
+}
+
+fn us() -> String {
+    "US".into()
+}
+
+/// Where the API lives.
+const API_URL: &str = "https://openapi.tidal.com/v2";
+
+impl Settings {
+    /// Fill in whatever we have stored from logging in.
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        let Credentials {
+            client_id,
+            client_secret,
+            refresh_token,
+            ..
+        } = credentials;
+        self.client_id = client_id.or(self.client_id);
+        self.client_secret = client_secret.or(self.client_secret);
+        self.refresh_token = refresh_token.or(self.refresh_token);
+        self
+    }
 }
 
 pub struct Tidal {
     data: Data<Self>,
-    app_access_token: Secret<String>,
-    user_access_token: Secret<String>,
+    app_token: Token,
+    user_token: Token,
+    /// Artist names by id, so tracks by artists we've already seen don't look them up again.
+    artists: DashMap<String, String>,
+    /// [`API_URL`], except in tests.
+    api_url: String,
 }
 
 impl Service for Tidal {
@@ -23,18 +61,50 @@ impl Service for Tidal {
     type Settings = Settings;
 
     async fn new(data: Data<Self>) -> eyre::Result<Self> {
-        let app_access_token = data.get_app_access_token().await?;
-        let user_access_token = data.get_user_access_token().await?;
+        let app_token = data.app_token()?;
+        let user_token = data.user_token()?;
+        // Make sure we can log in before going any further.
+        app_token.get().await?;
+        user_token.get().await?;
 
         Ok(Self {
             data,
-            app_access_token,
-            user_access_token,
+            app_token,
+            user_token,
+            artists: DashMap::new(),
+            api_url: API_URL.into(),
         })
     }
 
-    async fn run(&self) -> eyre::Result<()> {
-        self.update_playlist().await
+    fn data(&self) -> &Data<Self> {
+        &self.data
+    }
+
+    fn queries(&self, track: &Track) -> Vec<Query> {
+        let isrc = self.data.isrcs.get(track).map(Query::Isrc);
+        isrc.into_iter()
+            .chain([Query::Text(track.as_tidal_query())])
+            .collect()
+    }
+
+    async fn query(&self, query: &Query) -> eyre::Result<Option<Record>> {
+        match query {
+            Query::Isrc(isrc) => self.search_isrc(isrc).await,
+            Query::Text(text) => self.search_text(text).await,
+        }
+    }
+
+    fn playlist_id(&self) -> &str {
+        &self.data.settings.playlist_id
+    }
+
+    async fn update_playlist(&self, records: Vec<Record>) -> eyre::Result<()> {
+        let ids = records.into_iter().map(|r| r.id).collect::<Vec<_>>();
+
+        self.clear_playlist().await?;
+        self.add_tracks_to_playlist(ids).await?;
+
+        Ok(())
     }
 }
 
@@ -52,24 +122,114 @@ struct PlaylistItemMeta {
     item_id: String,
 }
 
-impl Tidal {
-    async fn update_playlist(&self) -> eyre::Result<()> {
-        let ids = self
-            .data
-            .search_all(|t| self.search(t))
-            .await
-            .into_iter()
-            .map(|r| r.id)
-            .collect::<Vec<_>>();
+/// Something from the `included` part of a compound document.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Included {
+    Tracks(TrackResource),
+    Artists(ArtistResource),
+    #[serde(other)]
+    Other,
+}
 
-        self.clear_playlist().await?;
-        self.add_tracks_to_playlist(ids).await?;
+/// A track, as Tidal's JSON:API responses represent it.
+#[derive(Deserialize, Debug)]
+struct TrackResource {
+    id: String,
+    attributes: TrackAttributes,
+    relationships: TrackRelationships,
+}
 
-        Ok(())
+#[derive(Deserialize, Debug)]
+struct TrackAttributes {
+    title: String,
+    isrc: Option<String>,
+    /// What the track can be used for in the country we asked about, e.g. `STREAM`.
+    #[serde(default)]
+    availability: Option<Vec<String>>,
+}
+
+impl TrackResource {
+    fn is_playable(&self) -> bool {
+        let playable = self
+            .attributes
+            .availability
+            .as_ref()
+            .is_none_or(|availability| availability.iter().any(|a| a == "STREAM"));
+        if !playable {
+            debug!(
+                id = self.id,
+                "skipping a track that isn't playable in our country"
+            );
+        }
+        playable
     }
+}
 
-    fn playlist_id(&self) -> &str {
-        &self.data.settings.playlist_id
+#[derive(Deserialize, Debug)]
+struct TrackRelationships {
+    artists: Relationship,
+}
+
+#[derive(Deserialize, Debug)]
+struct Relationship {
+    /// Only there when the related resources were included.
+    #[serde(default)]
+    data: Option<Vec<ResourceId>>,
+    links: RelationshipLinks,
+}
+
+#[derive(Deserialize, Debug)]
+struct ResourceId {
+    id: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RelationshipLinks {
+    #[serde(rename = "self")]
+    sel: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ArtistResource {
+    id: String,
+    attributes: ArtistAttributes,
+}
+
+#[derive(Deserialize, Debug)]
+struct ArtistAttributes {
+    name: String,
+}
+
+impl Tidal {
+    /// Talk to a mock server at `api_url`, with a token that's never refused.
+    #[cfg(test)]
+    fn mock(data: Data<Self>, api_url: String) -> Self {
+        let token = || {
+            Token::new(Self::NAME, "app", None, None, |_| async {
+                Ok(AuthResponse {
+                    access_token: Secret::new("token".into()),
+                    refresh_token: None,
+                    expires_in: None,
+                })
+            })
+        };
+        Self {
+            data,
+            app_token: token(),
+            user_token: token(),
+            artists: DashMap::new(),
+            api_url,
+        }
+    }
+
+    fn country_code(&self) -> &str {
+        &self.data.settings.country_code
+    }
+
+    /// The URL of an API path, e.g. `/tracks`.
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.api_url)
     }
 
     async fn get_playlist(&self) -> eyre::Result<Vec<PlaylistItem>> {
@@ -88,12 +248,12 @@ impl Tidal {
         let response: Response = self
             .data
             .client
-            .get(format!(
-                "https://openapi.tidal.com/v2/playlists/{}/relationships/items",
+            .get(self.url(&format!(
+                "/playlists/{}/relationships/items",
                 self.playlist_id()
-            ))
-            .query(&[("countryCode", "US")])
-            .bearer_auth(self.app_access_token.expose_secret())
+            )))
+            .query(&[("countryCode", self.country_code())])
+            .with_token(&self.app_token)
             .send_it_json()
             .await?;
         let mut cursor: Option<String> = response.links.next;
@@ -104,8 +264,8 @@ impl Tidal {
             let response: Response = self
                 .data
                 .client
-                .get(format!("https://openapi.tidal.com/v2{path}",))
-                .bearer_auth(self.app_access_token.expose_secret())
+                .get(self.url(&path))
+                .with_token(&self.app_token)
                 .send_it_json()
                 .await?;
             result.extend(response.data);
@@ -136,12 +296,12 @@ impl Tidal {
             debug!(%request_json, "clearing playlist");
             self.data
                 .client
-                .delete(format!(
-                    "https://openapi.tidal.com/v2/playlists/{}/relationships/items",
+                .delete(self.url(&format!(
+                    "/playlists/{}/relationships/items",
                     self.playlist_id()
-                ))
-                .bearer_auth(self.user_access_token.expose_secret())
+                )))
                 .json(&request)
+                .with_token(&self.user_token)
                 .send_it()
                 .await?;
         }
@@ -178,12 +338,12 @@ impl Tidal {
             debug!("adding tracks to playlist");
             self.data
                 .client
-                .post(format!(
-                    "https://openapi.tidal.com/v2/playlists/{}/relationships/items",
+                .post(self.url(&format!(
+                    "/playlists/{}/relationships/items",
                     self.playlist_id()
-                ))
-                .bearer_auth(self.user_access_token.expose_secret())
+                )))
                 .json(&request)
+                .with_token(&self.user_token)
                 .send_it()
                 .await?;
         }
@@ -191,149 +351,430 @@ impl Tidal {
         Ok(())
     }
 
-    async fn search(&self, track: &Track) -> eyre::Result<Option<Record>> {
+    async fn search_text(&self, query: &str) -> eyre::Result<Option<Record>> {
         #[derive(Deserialize, Debug)]
-        struct TrackResponse {
+        struct Response {
             #[serde(default)]
-            included: Vec<TrackIncluded>,
+            included: Vec<Included>,
         }
 
-        #[derive(Deserialize, Debug)]
-        struct TrackIncluded {
-            id: String,
-            #[serde(rename = "type")]
-            ty: String,
-            attributes: TrackAttributes,
-            relationships: Relationships,
-        }
-
-        #[derive(Deserialize, Debug)]
-        struct TrackAttributes {
-            title: String,
-        }
-
-        #[derive(Deserialize, Debug)]
-        struct Relationships {
-            artists: Relationship,
-        }
-
-        #[derive(Deserialize, Debug)]
-        struct Relationship {
-            links: Links,
-        }
-
-        #[derive(Deserialize, Debug)]
-        struct Links {
-            #[serde(rename = "self")]
-            sel: String,
-        }
+        // The query is a path segment, so anything like `/` or `?` in it has to be escaped.
+        let mut url = reqwest::Url::parse(&self.url("/searchResults"))?;
+        url.path_segments_mut()
+            .map_err(|()| eyre!("the Tidal API URL can't have a path"))?
+            .push(query);
 
         debug!("searching playlist");
-        let response: TrackResponse = self
+        let response: Response = self
             .data
             .client
-            .get(format!(
-                "https://openapi.tidal.com/v2/searchResults/{}",
-                track.as_tidal_query()
-            ))
-            .bearer_auth(self.app_access_token.expose_secret())
-            .query(&[("countryCode", "US"), ("include", "tracks")])
+            .get(url)
+            .query(&[
+                ("countryCode", self.country_code()),
+                ("include", "tracks,tracks.artists"),
+            ])
+            .with_token(&self.app_token)
             .send_it_json()
             .await?;
 
-        let Some(included) = response.included.into_iter().find(|inc| inc.ty == "tracks") else {
-            return Ok(None);
-        };
-
-        // To get the artist names, we have to query the link they sent us, which gives us the
-        // artist ids. Then we have to query for each id. Ugggh.
-        #[derive(Deserialize, Debug)]
-        struct ArtistIdResponse {
-            data: Vec<ArtistId>,
+        match self
+            .tracks(response.included)
+            .into_iter()
+            .find(TrackResource::is_playable)
+        {
+            Some(track) => self.record(track).await.map(Some),
+            None => Ok(None),
         }
+    }
 
+    async fn search_isrc(&self, isrc: &str) -> eyre::Result<Option<Record>> {
         #[derive(Deserialize, Debug)]
-        struct ArtistId {
-            id: String,
+        struct Response {
+            data: Vec<TrackResource>,
+            #[serde(default)]
+            included: Vec<Included>,
         }
 
-        let artist_data: ArtistIdResponse = self
+        debug!(%isrc, "searching by isrc");
+        let response: Response = self
             .data
             .client
-            .get(format!(
-                "https://openapi.tidal.com/v2{}",
-                included.relationships.artists.links.sel
-            ))
-            .bearer_auth(self.app_access_token.expose_secret())
+            .get(self.url("/tracks"))
+            .query(&[
+                ("countryCode", self.country_code()),
+                ("filter[isrc]", isrc),
+                ("include", "artists"),
+            ])
+            .with_token(&self.app_token)
             .send_it_json()
             .await?;
+        self.tracks(response.included);
 
-        #[derive(Deserialize, Debug)]
-        struct ArtistDataResponse {
-            data: ArtistData,
+        match response.data.into_iter().find(TrackResource::is_playable) {
+            Some(track) => self.record(track).await.map(Some),
+            None => Ok(None),
         }
+    }
 
+    /// Remember the names of the artists that were included alongside some tracks, and
+    /// return the tracks.
+    fn tracks(&self, included: Vec<Included>) -> Vec<TrackResource> {
+        included
+            .into_iter()
+            .filter_map(|included| match included {
+                Included::Tracks(track) => Some(track),
+                Included::Artists(artist) => {
+                    self.artists.insert(artist.id, artist.attributes.name);
+                    None
+                }
+                Included::Other => None,
+            })
+            .collect()
+    }
+
+    async fn record(&self, track: TrackResource) -> eyre::Result<Record> {
         #[derive(Deserialize, Debug)]
-        struct ArtistData {
-            attributes: ArtistAttributes,
+        struct ArtistIdResponse {
+            data: Vec<ResourceId>,
         }
 
+        let ids = match track.relationships.artists.data {
+            Some(ids) => ids,
+            None => {
+                debug!(id = track.id, "getting artist ids");
+                self.data
+                    .client
+                    .get(self.url(&track.relationships.artists.links.sel))
+                    .with_token(&self.app_token)
+                    .send_it_json::<ArtistIdResponse>()
+                    .await?
+                    .data
+            }
+        };
+        let ids = ids.into_iter().map(|id| id.id).collect::<Vec<_>>();
+        self.fetch_artists(&ids).await?;
+
+        let artists = ids
+            .iter()
+            .map(|id| {
+                self.artists
+                    .get(id)
+                    .map(|name| name.clone())
+                    .ok_or_else(|| eyre!("Tidal didn't return artist {id}"))
+            })
+            .collect::<eyre::Result<Vec<_>>>()?;
+
+        Ok(Record {
+            id: track.id,
+            title: track.attributes.title,
+            artists,
+            isrc: track.attributes.isrc,
+        })
+    }
+
+    /// Look up the names of whichever of these artists we haven't seen yet, a batch at a
+    /// time.
+    async fn fetch_artists(&self, ids: &[String]) -> eyre::Result<()> {
         #[derive(Deserialize, Debug)]
-        struct ArtistAttributes {
-            name: String,
+        struct Response {
+            data: Vec<ArtistResource>,
         }
 
-        let futures = artist_data.data.into_iter().map(|datum| {
-            let id = datum.id;
+        let missing = ids
+            .iter()
+            .filter(|id| !self.artists.contains_key(*id))
+            .unique()
+            .collect::<Vec<_>>();
 
-            self.data
+        for chunk in missing.chunks(20) {
+            debug!(?chunk, "getting artists");
+            let mut query = vec![("countryCode", self.country_code())];
+            query.extend(chunk.iter().map(|id| ("filter[id]", id.as_str())));
+            let response: Response = self
+                .data
                 .client
-                .get(format!("https://openapi.tidal.com/v2/artists/{id}"))
-                .query(&[("countryCode", "US")])
-                .bearer_auth(self.app_access_token.expose_secret())
-                .send_it_json::<ArtistDataResponse>()
-        });
-        let results = FuturesOrdered::from_iter(futures).collect::<Vec<_>>().await;
-
-        let artists = results
-            .into_iter()
-            .map(|r| r.map(|response| response.data.attributes.name))
-            .collect::<eyre::Result<Vec<_>>>()?;
+                .get(self.url("/artists"))
+                .query(&query)
+                .with_token(&self.app_token)
+                .send_it_json()
+                .await?;
+            for artist in response.data {
+                self.artists.insert(artist.id, artist.attributes.name);
+            }
+        }
 
-        Ok(Some(Record {
-            id: included.id,
-            title: included.attributes.title,
-            artists,
-        }))
+        Ok(())
     }
 }
 
 impl Data<Tidal> {
-    async fn get_app_access_token(&self) -> eyre::Result<Secret<String>> {
-        self.get_access_token(&[("grant_type", "client_credentials")])
-            .await
+    fn app_token(&self) -> eyre::Result<Token> {
+        self.token("app", None)
+    }
+
+    fn user_token(&self) -> eyre::Result<Token> {
+        let refresh_token = self.settings.refresh_token.clone().ok_or_eyre(
+            "no Tidal refresh token; run `playlister auth tidal` or set TIDAL__REFRESH_TOKEN",
+        )?;
+        self.token("user", Some(refresh_token))
     }
 
-    async fn get_user_access_token(&self) -> eyre::Result<Secret<String>> {
-        self.get_access_token(&[
+    /// Without a refresh token, the token is for the app rather than a user.
+    fn token(
+        &self,
+        grant: &'static str,
+        refresh_token: Option<Secret<String>>,
+    ) -> eyre::Result<Token> {
+        let (Some(client_id), Some(client_secret)) =
+            (&self.settings.client_id, &self.settings.client_secret)
+        else {
+            bail!(
+                "no Tidal client; run `playlister auth tidal` or set TIDAL__CLIENT_ID and TIDAL__CLIENT_SECRET"
+            );
+        };
+        let client = self.client.clone();
+        let client_id = client_id.clone();
+        let client_secret = client_secret.clone();
+        Ok(Token::new(
+            Tidal::NAME,
+            grant,
+            self.tokens.clone(),
+            refresh_token,
+            move |refresh_token| {
+                get_access_token(
+                    client.clone(),
+                    client_id.clone(),
+                    client_secret.clone(),
+                    refresh_token,
+                )
+            },
+        ))
+    }
+}
+
+async fn get_access_token(
+    client: reqwest::Client,
+    client_id: String,
+    client_secret: Secret<String>,
+    refresh_token: Option<Secret<String>>,
+) -> eyre::Result<AuthResponse> {
+    let body = match &refresh_token {
+        Some(refresh_token) => vec![
             ("grant_type", "refresh_token"),
-            ("refresh_token", self.settings.refresh_token.expose_secret()),
-        ])
-        .await
+            ("refresh_token", refresh_token.expose_secret().as_str()),
+        ],
+        None => vec![("grant_type", "client_credentials")],
+    };
+    let response: AuthResponse = client
+        .post("https://auth.tidal.com/v1/oauth2/token")
+        .basic_auth(&client_id, Some(client_secret.expose_secret()))
+        .form(&body)
+        .send_it_secret_json()
+        .await?;
+    debug!(expires_in = response.expires_in, "got access token");
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use wiremock::{
+        Mock, MockServer, Request, ResponseTemplate,
+        matchers::{method, path, query_param},
+    };
+
+    use crate::{Context, Service, cache::Cache, data::Data, track::Track};
+
+    use super::{Settings, Tidal};
+
+    /// Tracks with titles that are awkward in a URL, and the path we should search them at.
+    const PUNCTUATION: &[(&str, &str, &str)] = &[
+        (
+            "AC/DC",
+            "Back in Black",
+            "/v2/searchResults/AC%2FDC%20Back%20in%20Black",
+        ),
+        (
+            "Big Thief",
+            "What?",
+            "/v2/searchResults/Big%20Thief%20What%3F",
+        ),
+        (
+            "Garbage",
+            "#1 Crush",
+            "/v2/searchResults/Garbage%20%231%20Crush",
+        ),
+        ("Sault", "100%", "/v2/searchResults/Sault%20100%25"),
+        (
+            "Sigur Rós",
+            "Hoppípolla",
+            "/v2/searchResults/Sigur%20R%C3%B3s%20Hopp%C3%ADpolla",
+        ),
+        (
+            "Simon & Garfunkel",
+            "Mrs. Robinson",
+            "/v2/searchResults/Simon%20&%20Garfunkel%20Mrs.%20Robinson",
+        ),
+        (
+            "Florence + the Machine",
+            "Dog Days Are Over",
+            "/v2/searchResults/Florence%20+%20the%20Machine%20Dog%20Days%20Are%20Over",
+        ),
+        (
+            "Panic! at the Disco",
+            "\"Lying Is the Most Fun\"",
+            "/v2/searchResults/Panic!%20at%20the%20Disco%20%22Lying%20Is%20the%20Most%20Fun%22",
+        ),
+        (
+            "Kanye West",
+            "Good Morning (Intro)",
+            "/v2/searchResults/Kanye%20West%20Good%20Morning%20(Intro)",
+        ),
+    ];
+
+    fn tidal(server: &MockServer) -> Tidal {
+        let settings = Settings {
+            client_id: None,
+            client_secret: None,
+            refresh_token: None,
+            playlist_id: "playlist".into(),
+            country_code: "US".into(),
+        };
+        let data = Data::new(&Context::default(), &Cache::default(), settings);
+        Tidal::mock(data, format!("{}/v2", server.uri()))
     }
 
-    async fn get_access_token(&self, body: &[(&str, &str)]) -> eyre::Result<Secret<String>> {
-        let response: AuthResponse = self
-            .client
-            .post("https://auth.tidal.com/v1/oauth2/token")
-            .basic_auth(
-                &self.settings.client_id,
-                Some(self.settings.client_secret.expose_secret()),
-            )
-            .form(&body)
-            .send_it_json()
-            .await?;
+    #[tokio::test]
+    async fn test_punctuation() {
+        let server = MockServer::start().await;
+
+        for (i, (artist, title, search_path)) in PUNCTUATION.iter().enumerate() {
+            let track = json!({
+                "id": i.to_string(),
+                "type": "tracks",
+                "attributes": { "title": title },
+                "relationships": {
+                    "artists": {
+                        "data": [{ "id": i.to_string(), "type": "artists" }],
+                        "links": { "self": format!("/tracks/{i}/relationships/artists") },
+                    },
+                },
+            });
+            let artist = json!({
+                "id": i.to_string(),
+                "type": "artists",
+                "attributes": { "name": artist },
+            });
+            Mock::given(method("GET"))
+                .and(path(*search_path))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .set_body_json(json!({ "included": [track, artist] })),
+                )
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
 
-        Ok(response.access_token)
+        let tidal = tidal(&server);
+        for (i, (artist, title, _)) in PUNCTUATION.iter().enumerate() {
+            let track = Track::new(artist.to_string(), title.to_string());
+            let record = tidal.search(&track).await.unwrap().unwrap();
+            assert_eq!(i.to_string(), record.id, "{track}");
+            assert_eq!(*title, record.title, "{track}");
+            assert_eq!(vec![artist.to_string()], record.artists, "{track}");
+        }
+    }
+
+    /// The ids asked for in a batch artist lookup.
+    fn artist_ids(request: &Request) -> Vec<String> {
+        request
+            .url
+            .query_pairs()
+            .filter(|(key, _)| key == "filter[id]")
+            .map(|(_, id)| id.into_owned())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_artists() {
+        let server = MockServer::start().await;
+
+        let track = |id: &str, artists: Option<&[&str]>| {
+            let mut relationship = json!({
+                "links": { "self": format!("/tracks/{id}/relationships/artists") },
+            });
+            if let Some(artists) = artists {
+                relationship["data"] = json!(
+                    artists
+                        .iter()
+                        .map(|id| json!({ "id": id }))
+                        .collect::<Vec<_>>()
+                );
+            }
+            json!({
+                "data": [{
+                    "id": id,
+                    "type": "tracks",
+                    "attributes": { "title": format!("Track {id}") },
+                    "relationships": { "artists": relationship },
+                }],
+            })
+        };
+        Mock::given(method("GET"))
+            .and(path("/v2/tracks"))
+            .and(query_param("filter[isrc]", "A"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(track("a", Some(&["1", "2"]))))
+            .mount(&server)
+            .await;
+        // Without the artists included, the relationship link has to be followed.
+        Mock::given(method("GET"))
+            .and(path("/v2/tracks"))
+            .and(query_param("filter[isrc]", "B"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(track("b", None)))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/v2/tracks/b/relationships/artists"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "data": [{ "id": "2" }, { "id": "3" }] })),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let artists = |ids: &[&str]| {
+            let data = ids
+                .iter()
+                .map(|id| json!({ "id": id, "attributes": { "name": format!("Artist {id}") } }))
+                .collect::<Vec<_>>();
+            ResponseTemplate::new(200).set_body_json(json!({ "data": data }))
+        };
+        Mock::given(method("GET"))
+            .and(path("/v2/artists"))
+            .and(|request: &Request| artist_ids(request) == ["1", "2"])
+            .respond_with(artists(&["1", "2"]))
+            .expect(1)
+            .mount(&server)
+            .await;
+        // Artist 2 is already known by now.
+        Mock::given(method("GET"))
+            .and(path("/v2/artists"))
+            .and(|request: &Request| artist_ids(request) == ["3"])
+            .respond_with(artists(&["3"]))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let tidal = tidal(&server);
+        let record = tidal.search_isrc("A").await.unwrap().unwrap();
+        assert_eq!(vec!["Artist 1", "Artist 2"], record.artists);
+        let record = tidal.search_isrc("B").await.unwrap().unwrap();
+        assert_eq!(vec!["Artist 2", "Artist 3"], record.artists);
+        // Both of these are cached.
+        let record = tidal.search_isrc("A").await.unwrap().unwrap();
+        assert_eq!(vec!["Artist 1", "Artist 2"], record.artists);
     }
 }
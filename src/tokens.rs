@@ -0,0 +1,583 @@
+//! Credentials we got by logging in, and the access tokens they get us, kept between runs.
+
+use std::{
+    collections::BTreeMap,
+    fs::{File, TryLockError},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use eyre::{Context, bail};
+use futures::future::BoxFuture;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::{AuthResponse, JsonRequest, Secret, cache};
+
+/// What we've stored for a single service. Anything here takes precedence over the
+/// environment, as it's at least as recent.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Credentials {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<Secret<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<Secret<String>>,
+    /// Access tokens that may still be good, keyed by what they're for (e.g. `app` or
+    /// `user`).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub access_tokens: BTreeMap<String, AccessToken>,
+}
+
+/// A short-lived token for making requests.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AccessToken {
+    #[serde(deserialize_with = "crate::recent")]
+    token: Secret<String>,
+    /// In seconds since the unix epoch.
+    expires_at: u64,
+}
+
+impl AccessToken {
+    /// How long before it expires we stop using a token, so it doesn't run out between
+    /// being handed out and being used.
+    const MARGIN: u64 = 60;
+    /// For when a service doesn't say how long a token is good for. If it runs out
+    /// sooner, the request it's used in is retried with a new one.
+    const DEFAULT_LIFETIME: u64 = 60 * 60;
+
+    fn is_fresh(&self) -> bool {
+        self.expires_at > cache::now() + Self::MARGIN
+    }
+}
+
+/// A JSON file of credentials, keyed by service name. Only the current user can read it.
+///
+/// Credentials are shared by every job, except for the services a job has its own for,
+/// which are kept under `<job>/<service>` so that jobs don't run as each other.
+#[derive(Clone, Debug)]
+pub struct TokenStore {
+    path: PathBuf,
+    /// The job whose own credentials are used for each service, if any.
+    scopes: BTreeMap<String, String>,
+}
+
+impl TokenStore {
+    pub const FILE: &str = "tokens.json";
+    /// Held while the file is changed, and while a token is renewed, so that processes
+    /// sharing it (e.g. the daemon and a `sync` of another job) don't lose each other's
+    /// rotated refresh tokens.
+    const LOCK: &str = "tokens.lock";
+    /// Renewing a token takes one request, and changes only take as long as it takes to
+    /// rewrite a small file, so anything longer means something's wrong.
+    const LOCK_WAIT: Duration = Duration::from_secs(30);
+
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            path: dir.join(Self::FILE),
+            scopes: BTreeMap::new(),
+        }
+    }
+
+    /// Keep the service's credentials apart from every other job's.
+    pub fn scoped(mut self, service: &str, job: &str) -> Self {
+        self.scopes.insert(service.to_owned(), job.to_owned());
+        self
+    }
+
+    fn key(&self, service: &str) -> String {
+        match self.scopes.get(service) {
+            Some(job) => format!("{job}/{service}"),
+            None => service.to_owned(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The credentials stored for the service; empty if there aren't any.
+    pub fn get(&self, service: &str) -> eyre::Result<Credentials> {
+        Ok(self.load()?.remove(&self.key(service)).unwrap_or_default())
+    }
+
+    /// Change the credentials stored for the service, leaving other services alone.
+    pub async fn update(
+        &self,
+        service: &str,
+        f: impl FnOnce(&mut Credentials),
+    ) -> eyre::Result<()> {
+        let _lock = self.lock().await?;
+        self.write(service, f)
+    }
+
+    /// Change the credentials stored for the service, with the store already locked.
+    fn write(&self, service: &str, f: impl FnOnce(&mut Credentials)) -> eyre::Result<()> {
+        let mut all = self.load()?;
+        f(all.entry(self.key(service)).or_default());
+        let ser = serde_json::to_string_pretty(&all)?;
+        cache::write_private(&self.path, ser.as_bytes())
+    }
+
+    /// An exclusive lock on the store, held until the file is dropped. Waiting for it
+    /// doesn't hold up the runtime, so other jobs carry on in the meantime.
+    async fn lock(&self) -> eyre::Result<File> {
+        let path = self.path.with_file_name(Self::LOCK);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        let started = Instant::now();
+        loop {
+            match file.try_lock() {
+                Ok(()) => return Ok(file),
+                Err(TryLockError::WouldBlock) if started.elapsed() < Self::LOCK_WAIT => {
+                    tokio::time::sleep(Duration::from_millis(10)).await
+                }
+                Err(TryLockError::WouldBlock) => {
+                    bail!("timed out waiting for the lock on {}", path.display())
+                }
+                Err(TryLockError::Error(error)) => return Err(error.into()),
+            }
+        }
+    }
+
+    fn load(&self) -> eyre::Result<BTreeMap<String, Credentials>> {
+        if !self.path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        // Don't include the error's message; serde quotes what it failed on, which
+        // could be a token.
+        serde_json::from_str(&std::fs::read_to_string(&self.path)?)
+            .map_err(|error| eyre::eyre!("line {}, column {}", error.line(), error.column()))
+            .wrap_err_with(|| format!("failed to parse token file {}", self.path.display()))
+    }
+}
+
+type Fetch = Box<
+    dyn Fn(Option<Secret<String>>) -> BoxFuture<'static, eyre::Result<AuthResponse>> + Send + Sync,
+>;
+
+/// Hands out an access token, getting a new one when it's about to expire or when a
+/// service rejects it. Tokens are kept in the token store, so runs close together
+/// share them.
+pub struct Token {
+    service: &'static str,
+    /// What the token is for, e.g. `app` or `user`.
+    grant: &'static str,
+    store: Option<TokenStore>,
+    state: Mutex<TokenState>,
+    fetch: Fetch,
+}
+
+struct TokenState {
+    access: Option<AccessToken>,
+    /// What `fetch` is given, if the grant uses a refresh token.
+    refresh_token: Option<Secret<String>>,
+}
+
+impl Token {
+    /// `fetch` gets a new token from the service, given the current refresh token. If the
+    /// service rotates the refresh token, the new one is stored and used from then on.
+    pub(crate) fn new<F, Fut>(
+        service: &'static str,
+        grant: &'static str,
+        store: Option<TokenStore>,
+        refresh_token: Option<Secret<String>>,
+        fetch: F,
+    ) -> Self
+    where
+        F: Fn(Option<Secret<String>>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = eyre::Result<AuthResponse>> + Send + 'static,
+    {
+        let access = store.as_ref().and_then(|store| match store.get(service) {
+            Ok(mut credentials) => credentials.access_tokens.remove(grant),
+            Err(error) => {
+                warn!(%error, "failed to load stored access tokens");
+                None
+            }
+        });
+        Self {
+            service,
+            grant,
+            store,
+            state: Mutex::new(TokenState {
+                access,
+                refresh_token,
+            }),
+            fetch: Box::new(move |refresh_token| Box::pin(fetch(refresh_token))),
+        }
+    }
+
+    /// A token that's good for a while yet.
+    pub async fn get(&self) -> eyre::Result<Secret<String>> {
+        let mut state = self.state.lock().await;
+        if let Some(access) = &state.access
+            && access.is_fresh()
+        {
+            return Ok(access.token.clone());
+        }
+        self.renew(&mut state, None).await
+    }
+
+    /// A new token, after the service rejected the one we gave it. If another request
+    /// already replaced that one, it's not replaced again.
+    pub async fn refresh(&self, rejected: &Secret<String>) -> eyre::Result<Secret<String>> {
+        let mut state = self.state.lock().await;
+        if let Some(access) = &state.access
+            && access.token.expose_secret() != rejected.expose_secret()
+        {
+            return Ok(access.token.clone());
+        }
+        self.renew(&mut state, Some(rejected)).await
+    }
+
+    /// Get a new token, unless whoever else shares the credentials (another job, or
+    /// another process) has already stored one that isn't `rejected`.
+    ///
+    /// The store is locked until the new token is stored, so that they take turns with
+    /// the refresh token: the next one uses the token this rotates it to, rather than
+    /// the one the service has just stopped taking.
+    async fn renew(
+        &self,
+        state: &mut TokenState,
+        rejected: Option<&Secret<String>>,
+    ) -> eyre::Result<Secret<String>> {
+        let Some(store) = &self.store else {
+            return self.fetch_new(state, None).await;
+        };
+        let _lock = store.lock().await?;
+        match store.get(self.service) {
+            Ok(mut stored) => {
+                if let Some(access) = stored.access_tokens.remove(self.grant)
+                    && access.is_fresh()
+                    && rejected.is_none_or(|rejected| {
+                        rejected.expose_secret() != access.token.expose_secret()
+                    })
+                {
+                    state.access = Some(access.clone());
+                    return Ok(access.token);
+                }
+                if state.refresh_token.is_some() && stored.refresh_token.is_some() {
+                    state.refresh_token = stored.refresh_token;
+                }
+            }
+            Err(error) => warn!(service = self.service, %error, "failed to load stored tokens"),
+        }
+        self.fetch_new(state, Some(store)).await
+    }
+
+    /// Get a new token from the service, and store it in `store`, which has to be
+    /// locked.
+    async fn fetch_new(
+        &self,
+        state: &mut TokenState,
+        store: Option<&TokenStore>,
+    ) -> eyre::Result<Secret<String>> {
+        let response = (self.fetch)(state.refresh_token.clone()).await?;
+        let lifetime = response.expires_in.unwrap_or(AccessToken::DEFAULT_LIFETIME);
+        let access = AccessToken {
+            token: response.access_token,
+            expires_at: cache::now() + lifetime,
+        };
+        let rotated = match (response.refresh_token, &state.refresh_token) {
+            (Some(new), Some(old)) if new.expose_secret() != old.expose_secret() => Some(new),
+            _ => None,
+        };
+
+        self.save(store, &access, rotated.clone());
+        if rotated.is_some() {
+            state.refresh_token = rotated;
+        }
+        state.access = Some(access.clone());
+        Ok(access.token)
+    }
+
+    /// Failing to save doesn't stop this run, which has what it needs; the next one just
+    /// has to get its own token.
+    fn save(
+        &self,
+        store: Option<&TokenStore>,
+        access: &AccessToken,
+        rotated: Option<Secret<String>>,
+    ) {
+        let Some(store) = store else {
+            if rotated.is_some() {
+                warn!(
+                    service = self.service,
+                    "the refresh token was rotated, but there's nowhere to store it; set STATE_DIR"
+                );
+            }
+            return;
+        };
+        let stored_refresh_token = rotated.is_some();
+        let result = store.write(self.service, |credentials| {
+            credentials
+                .access_tokens
+                .insert(self.grant.to_string(), access.clone());
+            if let Some(refresh_token) = rotated {
+                credentials.refresh_token = Some(refresh_token);
+            }
+        });
+        match result {
+            Ok(()) if stored_refresh_token => {
+                info!(service = self.service, path = %store.path().display(), "stored rotated refresh token")
+            }
+            Ok(()) => (),
+            Err(error) => error!(service = self.service, %error, "failed to store tokens"),
+        }
+    }
+}
+
+/// Sending requests with an access token.
+pub trait Authenticate {
+    fn with_token(self, token: &Token) -> Authed<'_>;
+}
+
+impl Authenticate for RequestBuilder {
+    fn with_token(self, token: &Token) -> Authed<'_> {
+        Authed {
+            builder: self,
+            token,
+        }
+    }
+}
+
+/// A request that's sent with a bearer token. If the service rejects the token, the
+/// request is retried once with a new one.
+pub struct Authed<'a> {
+    builder: RequestBuilder,
+    token: &'a Token,
+}
+
+impl JsonRequest for Authed<'_> {
+    async fn send_request(self) -> eyre::Result<Response> {
+        // Only requests with a streaming body can't be cloned, and we don't send those.
+        let retry = self.builder.try_clone();
+        let token = self.token.get().await?;
+        let response = crate::send(self.builder.bearer_auth(token.expose_secret()), 0).await?;
+
+        match retry {
+            Some(retry) if response.status() == StatusCode::UNAUTHORIZED => {
+                warn!(
+                    service = self.token.service,
+                    grant = self.token.grant,
+                    "access token was rejected; retrying with a new one"
+                );
+                let token = self.token.refresh(&token).await?;
+                crate::send(retry.bearer_auth(token.expose_secret()), 1).await
+            }
+            _ => Ok(response),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    };
+
+    use crate::{AuthResponse, Secret};
+
+    use super::{Token, TokenStore};
+
+    #[tokio::test]
+    async fn test_tokens() {
+        let dir = tempfile::tempdir().unwrap();
+        let tokens = TokenStore::new(dir.path());
+        assert!(tokens.get("spotify").unwrap().refresh_token.is_none());
+
+        tokens
+            .update("spotify", |c| {
+                c.refresh_token = Some(Secret::new("aaa".into()))
+            })
+            .await
+            .unwrap();
+        tokens
+            .update("tidal", |c| {
+                c.refresh_token = Some(Secret::new("bbb".into()))
+            })
+            .await
+            .unwrap();
+        let spotify = tokens.get("spotify").unwrap().refresh_token.unwrap();
+        assert_eq!("aaa", spotify.expose_secret());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(tokens.path())
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(0o600, mode & 0o777);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_concurrent_updates() {
+        const SERVICES: [&str; 8] = ["a", "b", "c", "d", "e", "f", "g", "h"];
+        let dir = tempfile::tempdir().unwrap();
+        let tokens = TokenStore::new(dir.path());
+
+        // Each task has its own file handle, as another process would.
+        let tasks = SERVICES.map(|service| {
+            let tokens = tokens.clone();
+            tokio::spawn(async move {
+                for _ in 0..50 {
+                    tokens
+                        .update(service, |c| c.client_id = Some(service.to_owned()))
+                        .await
+                        .unwrap();
+                }
+            })
+        });
+        for task in tasks {
+            task.await.unwrap();
+        }
+        for service in SERVICES {
+            assert_eq!(
+                Some(service),
+                tokens.get(service).unwrap().client_id.as_deref()
+            );
+        }
+    }
+
+    /// A token whose fetches hand out `token-1`, `token-2`, ..., and rotate the refresh
+    /// token every time.
+    fn counting_token(store: &TokenStore, expires_in: u64) -> (Token, Arc<AtomicU64>) {
+        let fetches = Arc::new(AtomicU64::new(0));
+        let counter = fetches.clone();
+        let token = Token::new(
+            "spotify",
+            "user",
+            Some(store.clone()),
+            Some(Secret::new("refresh-0".into())),
+            move |refresh_token: Option<Secret<String>>| {
+                let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                let expected = format!("refresh-{}", n - 1);
+                async move {
+                    assert_eq!(&expected, refresh_token.unwrap().expose_secret());
+                    Ok(AuthResponse {
+                        access_token: Secret::new(format!("token-{n}")),
+                        refresh_token: Some(Secret::new(format!("refresh-{n}"))),
+                        expires_in: Some(expires_in),
+                    })
+                }
+            },
+        );
+        (token, fetches)
+    }
+
+    #[tokio::test]
+    async fn test_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TokenStore::new(dir.path());
+
+        let (token, fetches) = counting_token(&store, 3600);
+        let first = token.get().await.unwrap();
+        assert_eq!("token-1", first.expose_secret());
+        assert_eq!("token-1", token.get().await.unwrap().expose_secret());
+        assert_eq!(1, fetches.load(Ordering::SeqCst));
+
+        // Rejected, so we get a new one, but only once for the same rejected token.
+        assert_eq!(
+            "token-2",
+            token.refresh(&first).await.unwrap().expose_secret()
+        );
+        assert_eq!(
+            "token-2",
+            token.refresh(&first).await.unwrap().expose_secret()
+        );
+        assert_eq!(2, fetches.load(Ordering::SeqCst));
+
+        // The access token and rotated refresh token were stored, so the next run
+        // doesn't fetch at all.
+        let credentials = store.get("spotify").unwrap();
+        assert_eq!(
+            "refresh-2",
+            credentials.refresh_token.unwrap().expose_secret()
+        );
+        let (token, fetches) = counting_token(&store, 3600);
+        assert_eq!("token-2", token.get().await.unwrap().expose_secret());
+        assert_eq!(0, fetches.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_token_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TokenStore::new(dir.path());
+
+        // Tokens that are about to expire are replaced before they're used.
+        let (token, fetches) = counting_token(&store, 30);
+        assert_eq!("token-1", token.get().await.unwrap().expose_secret());
+        assert_eq!("token-2", token.get().await.unwrap().expose_secret());
+        assert_eq!(2, fetches.load(Ordering::SeqCst));
+    }
+
+    /// A token for a service that rotates the refresh token every time, and only takes
+    /// the latest one.
+    fn shared_token(store: &TokenStore, expires_in: u64, latest: &Arc<AtomicU64>) -> Token {
+        let latest = latest.clone();
+        Token::new(
+            "tidal",
+            "user",
+            Some(store.clone()),
+            Some(Secret::new("refresh-0".into())),
+            move |refresh_token: Option<Secret<String>>| {
+                let sent = refresh_token.unwrap().expose_secret().to_owned();
+                let n = latest.load(Ordering::SeqCst);
+                let result = if sent == format!("refresh-{n}") {
+                    latest.store(n + 1, Ordering::SeqCst);
+                    Ok(AuthResponse {
+                        access_token: Secret::new(format!("token-{}", n + 1)),
+                        refresh_token: Some(Secret::new(format!("refresh-{}", n + 1))),
+                        expires_in: Some(expires_in),
+                    })
+                } else {
+                    Err(eyre::eyre!("invalid_grant"))
+                };
+                async move { result }
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn test_shared_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TokenStore::new(dir.path());
+        let latest = Arc::new(AtomicU64::new(0));
+
+        // Both read refresh-0 at startup, but the second renews with the token the
+        // first rotated it to.
+        let (a, b) = (
+            shared_token(&store, 30, &latest),
+            shared_token(&store, 30, &latest),
+        );
+        assert_eq!("token-1", a.get().await.unwrap().expose_secret());
+        assert_eq!("token-2", b.get().await.unwrap().expose_secret());
+        assert_eq!("token-3", a.get().await.unwrap().expose_secret());
+        let stored = store.get("tidal").unwrap().refresh_token.unwrap();
+        assert_eq!("refresh-3", stored.expose_secret());
+
+        // A token the other one already renewed is used rather than renewed again.
+        let (a, b) = (
+            shared_token(&store, 3600, &latest),
+            shared_token(&store, 3600, &latest),
+        );
+        let first = a.get().await.unwrap();
+        assert_eq!("token-4", first.expose_secret());
+        assert_eq!("token-4", b.get().await.unwrap().expose_secret());
+        assert_eq!("token-5", a.refresh(&first).await.unwrap().expose_secret());
+        assert_eq!("token-5", b.refresh(&first).await.unwrap().expose_secret());
+        assert_eq!(5, latest.load(Ordering::SeqCst));
+    }
+}